@@ -1,7 +1,21 @@
+use std::path::PathBuf;
+use node::id_generator::IdFormat;
 use node::node::Node;
 
+// the id format comes from the first argument or the ID_FORMAT variable, snowflake when neither is set
+// ID_STATE_DIR keeps a high-water mark per node there, ID_LEASE_BLOCK leases blocks of that many ids from lin-kv
 #[tokio::main]
 async fn main() {
-    let mut node = Node::new().await;
+    let id_format = match std::env::args().nth(1).or_else(|| std::env::var("ID_FORMAT").ok()) {
+        Some(format) => format.parse::<IdFormat>().expect("error occur while parsing id format"),
+        None => IdFormat::default()
+    };
+    let mut node = Node::new().await.with_id_format(id_format);
+    if let Ok(state_dir) = std::env::var("ID_STATE_DIR") {
+        node = node.with_id_state_dir(PathBuf::from(state_dir));
+    }
+    if let Ok(block_size) = std::env::var("ID_LEASE_BLOCK") {
+        node = node.with_id_lease(block_size.parse().expect("error occur while parsing id lease block size"));
+    }
     let _ = node.run().await;
 }
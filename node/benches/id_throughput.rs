@@ -0,0 +1,35 @@
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+use node::id_generator::{IdFormat, IdGenerator};
+
+// ids a single node hands out per second, counter based formats also with their high-water mark on disk
+fn id_throughput(c: &mut Criterion) {
+    let formats = [
+        ("numeric", IdFormat::Numeric, true),
+        ("snowflake", IdFormat::Snowflake, true),
+        ("uuid-v4", IdFormat::UuidV4, false),
+        ("uuid-v7", IdFormat::UuidV7, false),
+        ("ulid", IdFormat::Ulid, false)
+    ];
+    let state_dir = std::env::temp_dir().join(format!("id-throughput-{}", std::process::id()));
+    std::fs::create_dir_all(&state_dir).expect("error occur while creating bench state dir");
+
+    let mut group = c.benchmark_group("generate");
+    group.throughput(Throughput::Elements(1));
+    for (name, format, persistent) in formats {
+        let mut generator = IdGenerator::new(format, 0, 3, None).expect("error occur while creating id generator");
+        group.bench_function(name, |b| b.iter(|| generator.generate()));
+        if !persistent {
+            continue
+        }
+
+        let state_path = state_dir.join(format!("{name}.ids"));
+        let mut generator = IdGenerator::new(format, 0, 3, Some(&state_path)).expect("error occur while reading id high-water mark");
+        group.bench_function(format!("{name}-persisted"), |b| b.iter(|| generator.generate()));
+    }
+    group.finish();
+
+    let _ = std::fs::remove_dir_all(&state_dir);
+}
+
+criterion_group!(benches, id_throughput);
+criterion_main!(benches);
@@ -34,7 +34,7 @@ impl Counter {
 
     pub async fn read(&self) -> i32 {
         let guard = self.value.lock().await;
-        guard.values().map(|v| v).sum()
+        guard.values().sum()
     }
 
     pub fn init_counter_replication(&mut self, cur_node: String, other_nodes: Vec<String>, out: mpsc::UnboundedSender<MessageForm>) {
@@ -1,22 +1,553 @@
-use std::ops::Add;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
+use crate::kv::{KvClient, KvError};
+
+// snowflake layout: 41 bits of milliseconds | 10 bits of node index | 12 bits of sequence
+const NODE_BITS: u32 = 10;
+const SEQUENCE_BITS: u32 = 12;
+const MAX_NODE_INDEX: u64 = (1 << NODE_BITS) - 1;
+const MAX_SEQUENCE: u64 = (1 << SEQUENCE_BITS) - 1;
+// 2024-01-01T00:00:00Z, keeps the timestamp part small
+const EPOCH_MS: u64 = 1_704_067_200_000;
+// how far ahead of the issued ids the persisted mark is moved at once
+const NUMERIC_RESERVE_BLOCK: u64 = 1000;
+const SNOWFLAKE_RESERVE_BLOCK_MS: u64 = 10_000;
+// lin-kv key holding the first id no node has leased yet
+const LEASE_KEY: &str = "id-lease";
+
+// id format handed out in `generate_ok`, picked once at startup
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum IdFormat {
+    // counter interleaved with the node index
+    Numeric,
+    #[default]
+    Snowflake,
+    UuidV4,
+    UuidV7,
+    Ulid
+}
+
+impl FromStr for IdFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "numeric" => Ok(IdFormat::Numeric),
+            "snowflake" => Ok(IdFormat::Snowflake),
+            "uuid-v4" => Ok(IdFormat::UuidV4),
+            "uuid-v7" => Ok(IdFormat::UuidV7),
+            "ulid" => Ok(IdFormat::Ulid),
+            _ => Err(format!("unknown id format {s}, expected one of numeric, snowflake, uuid-v4, uuid-v7, ulid"))
+        }
+    }
+}
+
+// why an id generator could not be set up
+#[derive(Debug)]
+pub enum IdError {
+    // snowflake ids only have room for node indexes up to 1023
+    NodeIndex {node_index: usize},
+    // the persisted high-water mark could not be read
+    State(io::Error)
+}
+
+impl Display for IdError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IdError::NodeIndex {node_index} => write!(f, "node index {node_index} does not fit into snowflake id"),
+            IdError::State(e) => write!(f, "error occur while reading id high-water mark: {e}")
+        }
+    }
+}
+
+impl Error for IdError {}
+
+impl From<io::Error> for IdError {
+    fn from(e: io::Error) -> Self {
+        IdError::State(e)
+    }
+}
+
+// fails only when persisting a high-water mark does, no id is issued then
+pub trait IdStrategy: Send {
+    fn generate(&mut self) -> io::Result<serde_json::Value>;
+}
+
+// persisted upper bound of everything this node may have issued
+// ids are only handed out below the mark, so a restarted node resumes above all of them
+pub struct HighWaterMark {
+    path: PathBuf,
+    reserved: u64,
+    block: u64
+}
+
+impl HighWaterMark {
+    pub fn open(path: &Path, block: u64) -> io::Result<Self> {
+        let reserved = match fs::read_to_string(path) {
+            Ok(content) => content.trim().parse::<u64>().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e)
+        };
+
+        Ok(HighWaterMark {
+            path: path.to_path_buf(),
+            reserved,
+            block
+        })
+    }
+
+    // first value that is safe to issue after a restart
+    pub fn start(&self) -> u64 {
+        self.reserved
+    }
+
+    // makes sure `value` is below the persisted mark, moving the mark a whole block ahead when it is not
+    pub fn reserve(&mut self, value: u64) -> io::Result<()> {
+        if value < self.reserved {
+            return Ok(())
+        }
+
+        let reserved = value + self.block;
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new(".")
+        };
+        fs::create_dir_all(dir)?;
+        let tmp_path = self.path.with_extension("tmp");
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(reserved.to_string().as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+        //the rename itself is only durable once the directory is synced
+        fs::File::open(dir)?.sync_all()?;
+
+        self.reserved = reserved;
+        Ok(())
+    }
+}
+
+// every node owns the ids congruent to its index modulo the cluster size
+pub struct NumericIds {
+    counter: u64,
+    node_index: u64,
+    node_count: u64,
+    high_water_mark: Option<HighWaterMark>
+}
+
+impl NumericIds {
+    pub fn new(node_index: usize, node_count: usize) -> Self {
+        NumericIds {
+            counter: 0,
+            node_index: node_index as u64,
+            node_count: node_count.max(1) as u64,
+            high_water_mark: None
+        }
+    }
+
+    // resumes the counter above everything reserved in `high_water_mark`
+    pub fn persistent(node_index: usize, node_count: usize, high_water_mark: HighWaterMark) -> Self {
+        let mut ids = NumericIds::new(node_index, node_count);
+        ids.counter = high_water_mark.start();
+        ids.high_water_mark = Some(high_water_mark);
+        ids
+    }
+}
+
+impl IdStrategy for NumericIds {
+    fn generate(&mut self) -> io::Result<serde_json::Value> {
+        if let Some(high_water_mark) = &mut self.high_water_mark {
+            high_water_mark.reserve(self.counter)?;
+        }
+        let id = self.counter * self.node_count + self.node_index;
+        self.counter += 1;
+        Ok(serde_json::Value::from(id))
+    }
+}
+
+pub struct SnowflakeIds {
+    node_index: u64,
+    last_timestamp: u64,
+    sequence: u64,
+    high_water_mark: Option<HighWaterMark>
+}
+
+impl SnowflakeIds {
+    // node_index is the position of the node in the cluster, it must be unique and below 1024
+    pub fn new(node_index: usize) -> Result<Self, IdError> {
+        if node_index as u64 > MAX_NODE_INDEX {
+            return Err(IdError::NodeIndex {node_index})
+        }
+        Ok(SnowflakeIds {
+            node_index: node_index as u64,
+            last_timestamp: 0,
+            sequence: 0,
+            high_water_mark: None
+        })
+    }
+
+    // a restarted node continues from the reserved timestamp even if its clock went backwards meanwhile
+    pub fn persistent(node_index: usize, high_water_mark: HighWaterMark) -> Result<Self, IdError> {
+        let mut ids = SnowflakeIds::new(node_index)?;
+        ids.last_timestamp = high_water_mark.start();
+        ids.sequence = MAX_SEQUENCE;
+        ids.high_water_mark = Some(high_water_mark);
+        Ok(ids)
+    }
+
+    fn now_ms() -> u64 {
+        let since_unix = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0);
+        since_unix.saturating_sub(EPOCH_MS)
+    }
+
+    pub fn next_id(&mut self) -> io::Result<u64> {
+        self.next_id_at(SnowflakeIds::now_ms())
+    }
+
+    // timestamps never go below the last issued one, so a clock moving backwards
+    // keeps issuing from the last millisecond and borrows the next one once its sequence runs out
+    fn next_id_at(&mut self, now_ms: u64) -> io::Result<u64> {
+        if now_ms > self.last_timestamp {
+            self.last_timestamp = now_ms;
+            self.sequence = 0;
+        } else if self.sequence == MAX_SEQUENCE {
+            self.last_timestamp += 1;
+            self.sequence = 0;
+        } else {
+            self.sequence += 1;
+        }
+
+        if let Some(high_water_mark) = &mut self.high_water_mark {
+            high_water_mark.reserve(self.last_timestamp)?;
+        }
+
+        Ok((self.last_timestamp << (NODE_BITS + SEQUENCE_BITS)) | (self.node_index << SEQUENCE_BITS) | self.sequence)
+    }
+}
+
+impl IdStrategy for SnowflakeIds {
+    fn generate(&mut self) -> io::Result<serde_json::Value> {
+        self.next_id().map(serde_json::Value::from)
+    }
+}
+
+pub struct UuidV4Ids;
+
+impl IdStrategy for UuidV4Ids {
+    fn generate(&mut self) -> io::Result<serde_json::Value> {
+        Ok(serde_json::Value::from(uuid::Uuid::new_v4().to_string()))
+    }
+}
+
+pub struct UuidV7Ids {
+    context: uuid::ContextV7
+}
+
+impl UuidV7Ids {
+    pub fn new() -> Self {
+        UuidV7Ids {
+            context: uuid::ContextV7::new()
+        }
+    }
+}
+
+impl Default for UuidV7Ids {
+    fn default() -> Self {
+        UuidV7Ids::new()
+    }
+}
+
+impl IdStrategy for UuidV7Ids {
+    fn generate(&mut self) -> io::Result<serde_json::Value> {
+        // the shared context keeps ids created within one millisecond ordered
+        let id = uuid::Uuid::new_v7(uuid::Timestamp::now(&self.context));
+        Ok(serde_json::Value::from(id.to_string()))
+    }
+}
+
+pub struct UlidIds {
+    generator: ulid::Generator
+}
+
+impl UlidIds {
+    pub fn new() -> Self {
+        UlidIds {
+            generator: ulid::Generator::new()
+        }
+    }
+}
+
+impl Default for UlidIds {
+    fn default() -> Self {
+        UlidIds::new()
+    }
+}
+
+impl IdStrategy for UlidIds {
+    fn generate(&mut self) -> io::Result<serde_json::Value> {
+        // monotonic generation only fails once the random part overflows within a millisecond
+        //a fresh random ulid could sort before the last one, so it spins into the next millisecond instead of parking the worker
+        let id = loop {
+            match self.generator.generate() {
+                Ok(id) => break id,
+                Err(_) => std::hint::spin_loop()
+            }
+        };
+        Ok(serde_json::Value::from(id.to_string()))
+    }
+}
 
 pub struct IdGenerator {
-    counter: u32,
-    node_id: String
+    strategy: Box<dyn IdStrategy>
 }
 
 impl IdGenerator {
-    pub fn new(node_id: String) -> Self {
+    // with `state_path` set, counter based formats persist their high-water mark there
+    pub fn new(format: IdFormat, node_index: usize, node_count: usize, state_path: Option<&Path>) -> Result<Self, IdError> {
+        let open_mark = |block: u64| state_path.map(|path| HighWaterMark::open(path, block)).transpose();
+
+        let strategy: Box<dyn IdStrategy> = match format {
+            IdFormat::Numeric => match open_mark(NUMERIC_RESERVE_BLOCK)? {
+                Some(mark) => Box::new(NumericIds::persistent(node_index, node_count, mark)),
+                None => Box::new(NumericIds::new(node_index, node_count))
+            },
+            IdFormat::Snowflake => match open_mark(SNOWFLAKE_RESERVE_BLOCK_MS)? {
+                Some(mark) => Box::new(SnowflakeIds::persistent(node_index, mark)?),
+                None => Box::new(SnowflakeIds::new(node_index)?)
+            },
+            IdFormat::UuidV4 => Box::new(UuidV4Ids),
+            IdFormat::UuidV7 => Box::new(UuidV7Ids::new()),
+            IdFormat::Ulid => Box::new(UlidIds::new()),
+        };
+
+        Ok(IdGenerator::with_strategy(strategy))
+    }
+
+    pub fn with_strategy(strategy: Box<dyn IdStrategy>) -> Self {
         IdGenerator {
-            counter: 0,
-            node_id
+            strategy
         }
     }
 
-    pub fn generate(&mut self) -> u32 {
-        let counter_str = self.counter.to_string();
-        let id = counter_str.add(&self.node_id[1..]).parse::<u32>().expect("error occur while generating unique id");
-        self.counter += 1;
-        id
+    pub fn generate(&mut self) -> io::Result<serde_json::Value> {
+        self.strategy.generate()
     }
-}
\ No newline at end of file
+}
+
+// hands out ids from contiguous blocks leased off a shared lin-kv counter
+// the next block is fetched while the current one is still half full, so short partitions go unnoticed
+#[derive(Clone)]
+pub struct IdLease {
+    kv: KvClient,
+    block_size: u64,
+    state: Arc<Mutex<LeaseState>>
+}
+
+struct LeaseState {
+    blocks: VecDeque<Range<u64>>,
+    prefetching: bool
+}
+
+impl IdLease {
+    pub fn new(kv: KvClient, block_size: u64) -> Self {
+        IdLease {
+            kv,
+            block_size: block_size.max(1),
+            state: Arc::new(Mutex::new(LeaseState {
+                blocks: VecDeque::new(),
+                prefetching: false
+            }))
+        }
+    }
+
+    pub async fn next_id(&self) -> Result<u64, KvError> {
+        loop {
+            let mut guard = self.state.lock().await;
+            while guard.blocks.front().is_some_and(|block| block.is_empty()) {
+                guard.blocks.pop_front();
+            }
+
+            if let Some(id) = guard.blocks.front_mut().and_then(|block| block.next()) {
+                let remaining = guard.blocks.iter().map(|block| block.end - block.start).sum::<u64>();
+                if remaining <= self.block_size / 2 && !guard.prefetching {
+                    guard.prefetching = true;
+                    self.spawn_prefetch();
+                }
+                return Ok(id)
+            }
+            drop(guard);
+
+            //nothing leased at all, the client has to wait for a block
+            let block = self.lease_block().await?;
+            self.state.lock().await.blocks.push_back(block);
+        }
+    }
+
+    fn spawn_prefetch(&self) {
+        let lease = self.clone();
+        tokio::spawn(async move {
+            let block = lease.lease_block().await;
+            let mut guard = lease.state.lock().await;
+            guard.prefetching = false;
+            match block {
+                Ok(block) => guard.blocks.push_back(block),
+                Err(e) => eprintln!("error occur while prefetching id block: {e}")
+            }
+        });
+    }
+
+    async fn lease_block(&self) -> Result<Range<u64>, KvError> {
+        loop {
+            let start = match self.kv.read(LEASE_KEY).await {
+                //no cas from a value it can't read would ever succeed
+                Ok(value) => value.as_u64().ok_or_else(|| KvError::Service {code: 12, text: format!("id lease counter holds {value}, not a u64")})?,
+                Err(KvError::KeyDoesNotExist) => 0,
+                Err(e) => return Err(e)
+            };
+            let end = start + self.block_size;
+
+            match self.kv.cas(LEASE_KEY, serde_json::Value::from(start), serde_json::Value::from(end), true).await {
+                Ok(()) => return Ok(start..end),
+                //another node leased this block first
+                Err(KvError::PreconditionFailed) => continue,
+                Err(e) => return Err(e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use tokio::sync::mpsc;
+    use crate::kv::LIN_KV;
+    use crate::message::{KvBody, MessageForm};
+    use proptest::prelude::*;
+
+    // per node: clock readings in the order the node sees them, jumps backwards included
+    fn clocks() -> impl Strategy<Value = Vec<Vec<u64>>> {
+        let reading = prop_oneof![
+            //mostly a steady clock, with the same millisecond seen many times
+            3 => Just(0i64),
+            3 => 1i64..3,
+            //the clock jumping backwards
+            1 => -50i64..0
+        ];
+        prop::collection::vec(prop::collection::vec(reading, 1..600), 1..8).prop_map(|nodes| {
+            nodes.into_iter().map(|steps| {
+                let mut now = 1_000i64;
+                steps.into_iter().map(|step| {
+                    now = (now + step).max(0);
+                    now as u64
+                }).collect()
+            }).collect()
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn snowflake_ids_are_unique_across_nodes(clocks in clocks()) {
+            let mut seen = HashSet::new();
+            for (node_index, readings) in clocks.into_iter().enumerate() {
+                let mut ids = SnowflakeIds::new(node_index).unwrap();
+                let mut last = None;
+                for now_ms in readings {
+                    let id = ids.next_id_at(now_ms).unwrap();
+                    //a node never goes back, whatever its clock does
+                    prop_assert!(last.is_none_or(|last| id > last));
+                    prop_assert!(seen.insert(id), "id {} issued twice", id);
+                    last = Some(id);
+                }
+            }
+        }
+
+        #[test]
+        fn numeric_ids_are_unique_across_nodes(counts in prop::collection::vec(0usize..300, 1..8)) {
+            let node_count = counts.len();
+            let mut seen = HashSet::new();
+            for (node_index, count) in counts.into_iter().enumerate() {
+                let mut ids = NumericIds::new(node_index, node_count);
+                for _ in 0..count {
+                    let id = ids.generate().unwrap();
+                    prop_assert!(seen.insert(id.as_u64().unwrap()));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn clock_going_back_keeps_issuing_from_last_millisecond() {
+        let mut ids = SnowflakeIds::new(3).unwrap();
+        let first = ids.next_id_at(100).unwrap();
+        let second = ids.next_id_at(40).unwrap();
+        assert_eq!(second >> (NODE_BITS + SEQUENCE_BITS), 100);
+        assert_eq!(second, first + 1);
+    }
+
+    #[test]
+    fn exhausted_sequence_borrows_next_millisecond() {
+        let mut ids = SnowflakeIds::new(0).unwrap();
+        for _ in 0..=MAX_SEQUENCE {
+            ids.next_id_at(100).unwrap();
+        }
+        let borrowed = ids.next_id_at(100).unwrap();
+        assert_eq!(borrowed >> (NODE_BITS + SEQUENCE_BITS), 101);
+        assert_eq!(borrowed & MAX_SEQUENCE, 0);
+
+        //the real clock catching up doesn't reissue the borrowed millisecond
+        let caught_up = ids.next_id_at(101).unwrap();
+        assert!(caught_up > borrowed);
+    }
+
+    #[test]
+    fn node_index_past_the_snowflake_layout_is_refused() {
+        assert!(SnowflakeIds::new(MAX_NODE_INDEX as usize).is_ok());
+        assert!(matches!(SnowflakeIds::new(MAX_NODE_INDEX as usize + 1), Err(IdError::NodeIndex {node_index: 1024})));
+        //other formats have no such limit
+        assert!(IdGenerator::new(IdFormat::Numeric, 2000, 2001, None).is_ok());
+    }
+
+    // answers the lease's next lin-kv request with `reply`
+    async fn answer(kv: &KvClient, rx: &mut mpsc::UnboundedReceiver<MessageForm>, reply: impl FnOnce(u32) -> KvBody) -> KvBody {
+        let Some(MessageForm::KvMessage(msg)) = rx.recv().await else { panic!("lease sent nothing to lin-kv") };
+        let msg_id = match msg.body {
+            KvBody::Read {msg_id, ..} | KvBody::Cas {msg_id, ..} | KvBody::Write {msg_id, ..} => msg_id,
+            _ => panic!("lease sent a reply to lin-kv")
+        };
+        kv.resolve(reply(msg_id)).await;
+        msg.body
+    }
+
+    #[tokio::test]
+    async fn lease_claims_the_block_after_the_counter() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let kv = KvClient::new(LIN_KV, String::from("n0"), tx);
+        let lease = IdLease::new(kv.clone(), 10);
+        let next = tokio::spawn(async move { lease.next_id().await });
+
+        answer(&kv, &mut rx, |in_reply_to| KvBody::ReadOk {in_reply_to, value: serde_json::Value::from(30)}).await;
+        let cas = answer(&kv, &mut rx, |in_reply_to| KvBody::CasOk {in_reply_to}).await;
+        assert!(matches!(cas, KvBody::Cas {from, to, ..} if from == 30 && to == 40));
+        assert_eq!(next.await.unwrap(), Ok(30));
+    }
+
+    #[tokio::test]
+    async fn lease_counter_that_is_not_a_u64_is_an_error() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let kv = KvClient::new(LIN_KV, String::from("n0"), tx);
+        let lease = IdLease::new(kv.clone(), 10);
+        let next = tokio::spawn(async move { lease.next_id().await });
+
+        answer(&kv, &mut rx, |in_reply_to| KvBody::ReadOk {in_reply_to, value: serde_json::Value::from(-5)}).await;
+        assert!(matches!(next.await.unwrap(), Err(KvError::Service {code: 12, ..})));
+    }
+}
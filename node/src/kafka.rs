@@ -1,12 +1,310 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+use serde::{Deserialize, Serialize};
+use crate::log_storage::{FsyncPolicy, LogStorage};
+use crate::message::Payload;
+use crate::snapshot::{self, SnapshotError, SnapshotLine};
+
+// group every client without an explicit one belongs to, so they all share committed offsets like maelstrom expects
+pub const DEFAULT_GROUP: &str = "default";
+
+// how many of a producer's latest sequence numbers are remembered per key
+const PRODUCER_SEQ_WINDOW: usize = 32;
+
+// how long an owner holds a prepared batch before asking its coordinator for the outcome
+pub const BATCH_LEASE: Duration = Duration::from_secs(5);
+
+// identifies a send so its retries are recognized
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProducerSeq {
+    pub producer_id: String,
+    pub seq: u64
+}
+
+#[derive(Debug)]
+pub enum KafkaError {
+    Io(io::Error),
+    // the sequence number is older than everything remembered for the producer, its offset is unknown
+    StaleSequence {producer_id: String, seq: u64},
+    // the key waits for the outcome of a prepared batch
+    KeyLocked {key: String, txn_id: String},
+    // the batch was already decided, a late prepare can't take part in it anymore
+    BatchFinished {txn_id: String}
+}
+
+impl Display for KafkaError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KafkaError::Io(e) => write!(f, "kafka storage error: {e}"),
+            KafkaError::StaleSequence {producer_id, seq} => write!(f, "sequence {seq} of producer {producer_id} is too old"),
+            KafkaError::KeyLocked {key, txn_id} => write!(f, "key {key} is locked by batch {txn_id}"),
+            KafkaError::BatchFinished {txn_id} => write!(f, "batch {txn_id} is already finished")
+        }
+    }
+}
+
+impl Error for KafkaError {}
+
+impl From<io::Error> for KafkaError {
+    fn from(e: io::Error) -> Self {
+        KafkaError::Io(e)
+    }
+}
+
+// how much of a key's log is kept, every limit left unset is unbounded
+// offsets never change, dropping old messages only moves the earliest offset of the key forward
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RetentionPolicy {
+    pub max_messages: Option<usize>,
+    pub max_age: Option<Duration>,
+    pub max_bytes: Option<usize>,
+    // opt-in compaction, only the latest message per value of this json field is kept
+    // compaction only happens in memory, on disk the compacted-away messages stay until their segment is dropped
+    // and are compacted again when the policy is set after a restart
+    pub compact_by: Option<String>
+}
+
+// caps on what a single poll returns, every limit left unset is unbounded
+// a poll always returns at least one message if any is available, so clients keep making progress
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PollLimits {
+    pub max_messages_per_key: Option<usize>,
+    pub max_messages: Option<usize>,
+    pub max_bytes: Option<usize>
+}
+
+impl PollLimits {
+    // limits set in the request win over the node's defaults
+    pub fn or(self, defaults: PollLimits) -> PollLimits {
+        PollLimits {
+            max_messages_per_key: self.max_messages_per_key.or(defaults.max_messages_per_key),
+            max_messages: self.max_messages.or(defaults.max_messages),
+            max_bytes: self.max_bytes.or(defaults.max_bytes)
+        }
+    }
+
+    // cuts already gathered messages down to the total limits, returns whether anything was cut
+    pub fn trim(&self, msgs: &mut HashMap<String, Vec<(usize, Payload)>>) -> bool {
+        let mut keys = msgs.keys().cloned().collect::<Vec<String>>();
+        keys.sort();
+
+        let mut budget = PollBudget::new(self);
+        let mut trimmed = false;
+        for key in keys {
+            let logs = msgs.get_mut(&key).unwrap();
+            let kept = logs.iter().take_while(|(_, msg)| budget.take(msg.to_string().len())).count();
+            trimmed |= kept < logs.len();
+            logs.truncate(kept);
+        }
+        trimmed
+    }
+}
+
+// what is left of the total limits while a poll is being filled
+struct PollBudget {
+    messages: Option<usize>,
+    bytes: Option<usize>,
+    taken: usize
+}
+
+impl PollBudget {
+    fn new(limits: &PollLimits) -> Self {
+        PollBudget {
+            messages: limits.max_messages,
+            bytes: limits.max_bytes,
+            taken: 0
+        }
+    }
+
+    fn take(&mut self, bytes: usize) -> bool {
+        let fits_messages = self.messages.is_none_or(|left| left > 0);
+        let fits_bytes = self.bytes.is_none_or(|left| left >= bytes) || self.taken == 0;
+        if !(fits_messages && fits_bytes) {
+            return false
+        }
+
+        self.messages = self.messages.map(|left| left - 1);
+        self.bytes = self.bytes.map(|left| left.saturating_sub(bytes));
+        self.taken += 1;
+        true
+    }
+}
+
+// what admin requests report about a key's log
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyStats {
+    pub earliest_offset: usize,
+    // offset the next message gets
+    pub high_watermark: usize,
+    pub messages: usize,
+    pub bytes: usize
+}
+
+struct Record {
+    offset: usize,
+    msg: Payload,
+    // wall clock time, so the age of a record survives a restart
+    appended_at: SystemTime,
+    // size of the serialized message
+    bytes: usize
+}
+
+impl Record {
+    // a clock set backwards makes a record look new rather than expired
+    fn age(&self) -> Duration {
+        self.appended_at.elapsed().unwrap_or_default()
+    }
+}
+
+// polls below the earliest retained offset start from the earliest one
+struct KeyLog {
+    records: VecDeque<Record>,
+    next_offset: usize,
+    bytes: usize,
+    // producer_id -> latest sequence numbers & offsets they were written at
+    producers: HashMap<String, BTreeMap<u64, usize>>
+}
+
+impl KeyLog {
+    fn starting_at(offset: usize) -> Self {
+        KeyLog {
+            records: VecDeque::new(),
+            next_offset: offset,
+            bytes: 0,
+            producers: HashMap::new()
+        }
+    }
+
+    // offset of an earlier send with the same sequence number
+    fn duplicate_of(&self, producer: &ProducerSeq) -> Result<Option<usize>, KafkaError> {
+        let Some(seqs) = self.producers.get(&producer.producer_id) else { return Ok(None) };
+
+        if let Some(offset) = seqs.get(&producer.seq) {
+            return Ok(Some(*offset))
+        }
+
+        let window_full = seqs.len() >= PRODUCER_SEQ_WINDOW;
+        if window_full && seqs.keys().next().is_some_and(|oldest| producer.seq < *oldest) {
+            return Err(KafkaError::StaleSequence {producer_id: producer.producer_id.clone(), seq: producer.seq})
+        }
+
+        Ok(None)
+    }
+
+    fn remember(&mut self, producer: &ProducerSeq, offset: usize) {
+        let seqs = self.producers.entry(producer.producer_id.clone()).or_default();
+        seqs.insert(producer.seq, offset);
+        if seqs.len() > PRODUCER_SEQ_WINDOW {
+            seqs.pop_first();
+        }
+    }
+
+    fn push(&mut self, msg: Payload, appended_at: SystemTime) -> usize {
+        let offset = self.next_offset;
+        let bytes = msg.to_string().len();
+        self.records.push_back(Record {offset, msg, appended_at, bytes});
+        self.bytes += bytes;
+        self.next_offset += 1;
+        offset
+    }
+
+    // records restored from a snapshot keep their offsets, compaction may have left gaps between them
+    fn push_at(&mut self, offset: usize, msg: Payload, appended_at: SystemTime) {
+        self.next_offset = offset;
+        self.push(msg, appended_at);
+    }
+
+    fn has_gaps(&self) -> bool {
+        self.records.len() < self.next_offset - self.earliest_offset()
+    }
+
+    fn earliest_offset(&self) -> usize {
+        self.records.front().map(|record| record.offset).unwrap_or(self.next_offset)
+    }
+
+    fn drop_before(&mut self, offset: usize) {
+        while self.records.front().is_some_and(|oldest| oldest.offset < offset) {
+            let oldest = self.records.pop_front().unwrap();
+            self.bytes -= oldest.bytes;
+        }
+    }
+
+    fn stats(&self) -> KeyStats {
+        KeyStats {
+            earliest_offset: self.earliest_offset(),
+            high_watermark: self.next_offset,
+            messages: self.records.len(),
+            bytes: self.bytes
+        }
+    }
+
+    fn enforce(&mut self, policy: &RetentionPolicy) {
+        if let Some(field) = &policy.compact_by {
+            self.compact(field);
+        }
+
+        while let Some(oldest) = self.records.front() {
+            let too_many = policy.max_messages.is_some_and(|max| self.records.len() > max);
+            let too_big = policy.max_bytes.is_some_and(|max| self.bytes > max);
+            let too_old = policy.max_age.is_some_and(|max| oldest.age() > max);
+            if !(too_many || too_big || too_old) {
+                break
+            }
+
+            let oldest = self.records.pop_front().unwrap();
+            self.bytes -= oldest.bytes;
+        }
+    }
+
+    // keeps the newest record of every sub-key, messages without the field are never compacted away
+    fn compact(&mut self, field: &str) {
+        let mut seen = HashSet::new();
+        let mut kept = VecDeque::with_capacity(self.records.len());
+        while let Some(record) = self.records.pop_back() {
+            let keep = match record.msg.0.get(field) {
+                Some(sub_key) => seen.insert(sub_key.to_string()),
+                None => true
+            };
+
+            if keep {
+                kept.push_front(record);
+            } else {
+                self.bytes -= record.bytes;
+            }
+        }
+        self.records = kept;
+    }
+}
 
 pub struct Kafka {
-    // storage for logs that client have seen last time
-    // client_id -> log_key & last seen offset for its log_key
+    // offsets committed by every consumer group
+    // group_id -> log_key & committed offset for its log_key
     last_seen_logs: HashMap<String, HashMap<String, usize>>,
     // storage for logs
     // log_key & logs for its log_key
-    logs: HashMap<String, Vec<u32>>
+    logs: HashMap<String, KeyLog>,
+    // disk copy of logs and committed offsets, logs only live in memory when unset
+    storage: Option<LogStorage>,
+    retention: RetentionPolicy,
+    // log_key -> policy overriding `retention` for it
+    key_retention: HashMap<String, RetentionPolicy>,
+    // batches prepared here and waiting for their coordinator's decision
+    // txn_id -> log_key & offset handed out and message to append there on commit
+    prepared: HashMap<String, HashMap<String, (usize, Payload)>>,
+    // log_key -> txn_id of the prepared batch holding it
+    locked_keys: HashMap<String, String>,
+    // ids of committed or aborted batches
+    finished: HashSet<String>
+}
+
+impl Default for Kafka {
+    fn default() -> Self {
+        Kafka::new()
+    }
 }
 
 impl Kafka {
@@ -14,54 +312,339 @@ impl Kafka {
     pub fn new() -> Self {
         Kafka {
             last_seen_logs: HashMap::new(),
-            logs: HashMap::new()
+            logs: HashMap::new(),
+            storage: None,
+            retention: RetentionPolicy::default(),
+            key_retention: HashMap::new(),
+            prepared: HashMap::new(),
+            locked_keys: HashMap::new(),
+            finished: HashSet::new()
         }
     }
 
-    pub fn write_log(&mut self, key: String, msg: u32) -> usize {
-        match self.logs.get_mut(&key) {
-            Some(logs) => {
-                let logs_len = logs.len();
-                logs.push(msg);
-                logs_len
-            },
-            None => {
-                self.logs.insert(key, vec![msg]);
-                0
+    // loads whatever survived on disk in `dir` and keeps persisting there
+    pub fn open(dir: &Path, fsync: FsyncPolicy) -> io::Result<Self> {
+        let (storage, recovered) = LogStorage::open(dir, fsync)?;
+
+        let logs = recovered.logs.into_iter().map(|(key, (start_offset, msgs))| {
+            let mut log = KeyLog::starting_at(start_offset);
+            for (msg, appended_at) in msgs {
+                log.push(msg, appended_at);
             }
+            (key, log)
+        }).collect();
+
+        Ok(Kafka {
+            last_seen_logs: recovered.committed,
+            logs,
+            storage: Some(storage),
+            retention: RetentionPolicy::default(),
+            key_retention: HashMap::new(),
+            prepared: HashMap::new(),
+            locked_keys: HashMap::new(),
+            finished: HashSet::new()
+        })
+    }
+
+    pub fn set_retention(&mut self, retention: RetentionPolicy) -> io::Result<()> {
+        self.retention = retention;
+        let keys = self.logs.keys().cloned().collect::<Vec<String>>();
+        for key in keys {
+            self.enforce_retention(&key)?;
         }
+        Ok(())
     }
 
-    fn get_logs_from_offset(&self, log_key: &String, offset: usize) -> Option<Vec<(usize, u32)>> {
-        match self.logs.get(log_key) {
-            Some(logs) => {
-                let logs = logs.iter().enumerate().skip(offset).map(|(index, el)| (index, *el)).collect::<Vec<(usize, u32)>>();
-                Some(logs)
-            },
-            None => None
+    pub fn set_key_retention(&mut self, key: String, retention: RetentionPolicy) -> io::Result<()> {
+        self.key_retention.insert(key.clone(), retention);
+        self.enforce_retention(&key)
+    }
+
+    fn enforce_retention(&mut self, key: &String) -> io::Result<()> {
+        let policy = self.key_retention.get(key).unwrap_or(&self.retention);
+        let Some(log) = self.logs.get_mut(key) else { return Ok(()) };
+
+        log.enforce(policy);
+
+        //compaction leaves holes, so only whole segments below the earliest offset go away on disk
+        //what was compacted away stays in the segments and is compacted again once the node restarts
+        match &mut self.storage {
+            Some(storage) => storage.drop_before(key, log.earliest_offset()),
+            None => Ok(())
         }
+    }
+
+    // a retried send of the same producer sequence gets its original offset back instead of being appended again
+    pub fn write_log(&mut self, key: String, msg: Payload, producer: Option<&ProducerSeq>) -> Result<usize, KafkaError> {
+        if let Some(txn_id) = self.locked_keys.get(&key) {
+            return Err(KafkaError::KeyLocked {key, txn_id: txn_id.clone()})
+        }
+
+        self.append(key, msg, producer)
+    }
+
+    // appends whether or not a batch holds the key
+    fn append(&mut self, key: String, msg: Payload, producer: Option<&ProducerSeq>) -> Result<usize, KafkaError> {
+        let log = self.logs.entry(key.clone()).or_insert_with(|| KeyLog::starting_at(0));
+
+        if let Some(producer) = producer && let Some(offset) = log.duplicate_of(producer)? {
+            return Ok(offset)
+        }
+
+        let now = SystemTime::now();
+        if let Some(storage) = &mut self.storage {
+            storage.append(&key, log.next_offset, &msg, now)?;
+        }
+
+        let offset = log.push(msg, now);
+        if let Some(producer) = producer {
+            log.remember(producer, offset);
+        }
+        self.enforce_retention(&key)?;
+        Ok(offset)
+    }
+
+    fn next_offset(&self, key: &str) -> usize {
+        self.logs.get(key).map(|log| log.next_offset).unwrap_or(0)
+    }
+
+    // first phase of an atomic batch: locks its keys and returns the offsets the messages get on commit
+    // nothing is appended until `commit_batch`, so polls never see part of a batch
+    pub fn prepare_batch(&mut self, txn_id: String, msgs: HashMap<String, Payload>) -> Result<HashMap<String, usize>, KafkaError> {
+        if self.finished.contains(&txn_id) {
+            return Err(KafkaError::BatchFinished {txn_id})
+        }
+
+        //a retried prepare gets the offsets handed out the first time
+        if let Some(prepared) = self.prepared.get(&txn_id) {
+            return Ok(prepared.iter().map(|(key, (offset, _))| (key.clone(), *offset)).collect())
+        }
+
+        for key in msgs.keys() {
+            if let Some(holder) = self.locked_keys.get(key) {
+                return Err(KafkaError::KeyLocked {key: key.clone(), txn_id: holder.clone()})
+            }
+        }
+
+        let mut offsets = HashMap::new();
+        let mut prepared = HashMap::new();
+        for (key, msg) in msgs {
+            let offset = self.next_offset(&key);
+            self.locked_keys.insert(key.clone(), txn_id.clone());
+            offsets.insert(key.clone(), offset);
+            prepared.insert(key, (offset, msg));
+        }
+        self.prepared.insert(txn_id, prepared);
+        Ok(offsets)
+    }
 
+    // appends a prepared batch at the offsets handed out by `prepare_batch`
+    // the batch stays prepared and its keys locked until all of it is appended, so a retried commit finishes it
+    // committing an unknown batch is a no-op, the coordinator retries until it hears back
+    pub fn commit_batch(&mut self, txn_id: String) -> Result<(), KafkaError> {
+        let Some(msgs) = self.prepared.get(&txn_id) else {
+            self.finished.insert(txn_id);
+            return Ok(())
+        };
+
+        //keys an earlier attempt appended to moved past their prepared offset
+        let pending = msgs.iter()
+            .filter(|(key, (offset, _))| self.next_offset(key) == *offset)
+            .map(|(key, (_, msg))| (key.clone(), msg.clone()))
+            .collect::<Vec<(String, Payload)>>();
+        for (key, msg) in pending {
+            self.append(key, msg, None)?;
+        }
+
+        let msgs = self.prepared.remove(&txn_id).unwrap();
+        for key in msgs.keys() {
+            self.locked_keys.remove(key);
+        }
+        self.finished.insert(txn_id);
+        Ok(())
     }
 
-    pub fn read_logs(&self, offsets: HashMap<String, usize>) -> HashMap<String, Vec<(usize, u32)>> {
+    // also refuses a prepare of the batch arriving after the abort
+    pub fn abort_batch(&mut self, txn_id: String) {
+        if let Some(msgs) = self.prepared.remove(&txn_id) {
+            for key in msgs.keys() {
+                self.locked_keys.remove(key);
+            }
+        }
+        self.finished.insert(txn_id);
+    }
+
+    // returns the messages and whether the log goes on past them
+    fn get_logs_from_offset(&self, log_key: &String, offset: usize, limits: &PollLimits, budget: &mut PollBudget) -> Option<(Vec<(usize, Payload)>, bool)> {
+        let log = self.logs.get(log_key)?;
+        let max_age = self.key_retention.get(log_key).unwrap_or(&self.retention).max_age;
+        let max_per_key = limits.max_messages_per_key.unwrap_or(usize::MAX);
+
+        let from = log.records.partition_point(|record| record.offset < offset);
+        let mut records = log.records.range(from..)
+            //expired messages stay hidden until the next write drops them
+            .filter(|record| max_age.is_none_or(|max| record.age() <= max))
+            .peekable();
+
+        let mut logs = Vec::new();
+        while let Some(record) = records.peek() {
+            if logs.len() >= max_per_key || !budget.take(record.bytes) {
+                break
+            }
+            logs.push((record.offset, record.msg.clone()));
+            records.next();
+        }
+
+        let more = records.peek().is_some();
+        Some((logs, more))
+    }
+
+    // every key is read in key order, so a poll that ran out of budget continues with the same keys next time
+    pub fn read_logs(&self, offsets: HashMap<String, usize>, limits: &PollLimits) -> (HashMap<String, Vec<(usize, Payload)>>, bool) {
         let mut logs = HashMap::new();
+        let mut more = false;
+
+        let mut offsets = offsets.into_iter().collect::<Vec<(String, usize)>>();
+        offsets.sort();
 
+        let mut budget = PollBudget::new(limits);
         for (log_k, offset) in offsets {
-            if let Some(logs_from_offset) = self.get_logs_from_offset(&log_k, offset) {
+            if let Some((logs_from_offset, key_has_more)) = self.get_logs_from_offset(&log_k, offset, limits, &mut budget) {
                 logs.insert(log_k, logs_from_offset);
+                more |= key_has_more;
             }
 
         }
 
-        logs
+        (logs, more)
+    }
+
+    // merged into what the group committed before, an offset never moves backwards
+    pub fn commit_offsets(&mut self, group: String, offsets: HashMap<String, usize>) -> io::Result<()> {
+        let committed = self.last_seen_logs.entry(group).or_default();
+        for (key, offset) in offsets {
+            let committed_offset = committed.entry(key).or_insert(offset);
+            *committed_offset = (*committed_offset).max(offset);
+        }
+
+        match &self.storage {
+            Some(storage) => storage.save_committed(&self.last_seen_logs),
+            None => Ok(())
+        }
+    }
+
+    pub fn keys(&self) -> Vec<String> {
+        let mut keys = self.logs.keys().cloned().collect::<Vec<String>>();
+        keys.sort();
+        keys
+    }
+
+    // keys without a log here are left out
+    pub fn describe_keys(&self, keys: Vec<String>) -> HashMap<String, KeyStats> {
+        keys.into_iter()
+            .filter_map(|key| self.logs.get(&key).map(|log| (key.clone(), log.stats())))
+            .collect()
+    }
+
+    // group_id -> log_key & number of messages after its committed offset
+    pub fn group_lags(&self) -> HashMap<String, HashMap<String, usize>> {
+        self.last_seen_logs.iter().map(|(group, offsets)| {
+            let lags = offsets.iter().map(|(key, committed)| {
+                (key.clone(), self.next_offset(key).saturating_sub(committed + 1))
+            }).collect();
+            (group.clone(), lags)
+        }).collect()
+    }
+
+    // drops every message below `offset`, the next offset handed out stays the same
+    // only whole segments go away on disk, the new earliest offset is saved so the rest stays dropped after a restart
+    pub fn truncate_key(&mut self, key: &String, offset: usize) -> io::Result<()> {
+        let Some(log) = self.logs.get_mut(key) else { return Ok(()) };
+
+        log.drop_before(offset);
+        match &mut self.storage {
+            Some(storage) => {
+                storage.save_start_offset(key, log.earliest_offset())?;
+                storage.drop_before(key, log.earliest_offset())
+            },
+            None => Ok(())
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.logs.is_empty() && self.last_seen_logs.is_empty()
+    }
+
+    // dumps every log and committed offset as json lines, see `SnapshotLine`
+    pub fn export_snapshot(&self, path: &Path) -> io::Result<()> {
+        let mut keys = self.logs.keys().collect::<Vec<&String>>();
+        keys.sort();
+
+        let logs = keys.into_iter().flat_map(|key| {
+            let log = &self.logs[key];
+            let header = SnapshotLine::Key {
+                key: key.clone(),
+                earliest_offset: log.earliest_offset(),
+                high_watermark: log.next_offset,
+                compacted: log.has_gaps()
+            };
+            let records = log.records.iter().map(|record| SnapshotLine::Record {key: key.clone(), offset: record.offset, msg: record.msg.clone()});
+            std::iter::once(header).chain(records)
+        });
+        let committed = self.last_seen_logs.iter().flat_map(|(group, offsets)| {
+            offsets.iter().map(|(key, offset)| SnapshotLine::Committed {group: group.clone(), key: key.clone(), offset: *offset})
+        });
+
+        snapshot::write_snapshot(path, logs.chain(committed))
     }
 
-    pub fn commit_offsets(&mut self, client: String, offsets: HashMap<String, usize>) {
-        self.last_seen_logs.insert(client, offsets);
+    // restores the logs and committed offsets of the keys `keep` accepts, only into a kafka holding nothing yet
+    // the whole snapshot is checked before anything is restored
+    pub fn import_snapshot(&mut self, path: &Path, keep: impl Fn(&str) -> bool) -> Result<(), SnapshotError> {
+        if !self.is_empty() {
+            return Err(SnapshotError::Rejected(String::from("kafka already holds logs or committed offsets")))
+        }
+
+        let mut snapshot = snapshot::read_snapshot(path)?;
+        snapshot.logs.retain(|key, _| keep(key));
+        for offsets in snapshot.committed.values_mut() {
+            offsets.retain(|key, _| keep(key));
+        }
+
+        //segments on disk hold consecutive offsets only
+        if self.storage.is_some() {
+            let gapped = snapshot.logs.iter().find(|(_, log)| log.records.len() < log.high_watermark - log.earliest_offset);
+            if let Some((key, _)) = gapped {
+                return Err(SnapshotError::Rejected(format!("compacted key {key} can't be stored on disk")))
+            }
+        }
+
+        let now = SystemTime::now();
+        for (key, restored) in snapshot.logs {
+            let mut log = KeyLog::starting_at(restored.earliest_offset);
+            if let Some(storage) = &mut self.storage {
+                storage.start_key_at(&key, restored.earliest_offset)?;
+            }
+            for (offset, msg) in restored.records {
+                if let Some(storage) = &mut self.storage {
+                    storage.append(&key, offset, &msg, now)?;
+                }
+                log.push_at(offset, msg, now);
+            }
+            log.next_offset = restored.high_watermark;
+            self.logs.insert(key, log);
+        }
+
+        self.last_seen_logs = snapshot.committed;
+        self.last_seen_logs.retain(|_, offsets| !offsets.is_empty());
+        if let Some(storage) = &self.storage {
+            storage.save_committed(&self.last_seen_logs)?;
+        }
+        Ok(())
     }
 
-    pub fn get_commited_offsets(&self, client: &String, log_keys: Vec<String>) -> HashMap<String, usize> {
-        match self.last_seen_logs.get(client) {
+    pub fn get_commited_offsets(&self, group: &String, log_keys: Vec<String>) -> HashMap<String, usize> {
+        match self.last_seen_logs.get(group) {
             Some(offsets) => {
                 log_keys
                     .into_iter()
@@ -75,4 +658,86 @@ impl Kafka {
             }
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn reopen(dir: &Path) -> Kafka {
+        Kafka::open(dir, FsyncPolicy::Always).unwrap()
+    }
+
+    fn read_from_start(kafka: &Kafka, key: &str) -> Vec<(usize, Payload)> {
+        let (mut logs, _) = kafka.read_logs(HashMap::from([(String::from(key), 0)]), &PollLimits::default());
+        logs.remove(key).unwrap_or_default()
+    }
+
+    #[test]
+    fn batch_commit_retried_after_a_storage_error_appends_every_message_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut kafka = reopen(dir.path());
+        let msgs = HashMap::from([(String::from("a"), Payload::from(1)), (String::from("b"), Payload::from(2))]);
+        kafka.prepare_batch(String::from("t1"), msgs).unwrap();
+
+        //a file where the directory of key b goes fails its append
+        std::fs::write(dir.path().join("62"), b"").unwrap();
+        assert!(matches!(kafka.commit_batch(String::from("t1")), Err(KafkaError::Io(_))));
+        assert!(matches!(kafka.write_log(String::from("a"), Payload::from(3), None), Err(KafkaError::KeyLocked {..})));
+
+        std::fs::remove_file(dir.path().join("62")).unwrap();
+        kafka.commit_batch(String::from("t1")).unwrap();
+
+        assert_eq!(read_from_start(&kafka, "a"), vec![(0, Payload::from(1))]);
+        assert_eq!(read_from_start(&kafka, "b"), vec![(0, Payload::from(2))]);
+        assert_eq!(kafka.write_log(String::from("a"), Payload::from(3), None).unwrap(), 1);
+    }
+
+    #[test]
+    fn truncated_messages_stay_dropped_after_a_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut kafka = reopen(dir.path());
+        for value in 0..3 {
+            kafka.write_log(String::from("k"), Payload::from(value), None).unwrap();
+        }
+        kafka.truncate_key(&String::from("k"), 2).unwrap();
+        drop(kafka);
+
+        let mut kafka = reopen(dir.path());
+
+        assert_eq!(read_from_start(&kafka, "k"), vec![(2, Payload::from(2))]);
+        assert_eq!(kafka.write_log(String::from("k"), Payload::from(3), None).unwrap(), 3);
+    }
+
+    #[test]
+    fn record_age_survives_a_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut kafka = reopen(dir.path());
+        kafka.write_log(String::from("k"), Payload::from(1), None).unwrap();
+        drop(kafka);
+        std::thread::sleep(Duration::from_millis(300));
+
+        let mut kafka = reopen(dir.path());
+        kafka.set_retention(RetentionPolicy {max_age: Some(Duration::from_millis(200)), ..RetentionPolicy::default()}).unwrap();
+
+        assert_eq!(kafka.describe_keys(vec![String::from("k")])["k"].messages, 0);
+        assert!(read_from_start(&kafka, "k").is_empty());
+    }
+
+    #[test]
+    fn compacted_values_stay_compacted_after_a_reopen() {
+        let compacted = RetentionPolicy {compact_by: Some(String::from("id")), ..RetentionPolicy::default()};
+        let dir = tempfile::tempdir().unwrap();
+        let mut kafka = reopen(dir.path());
+        kafka.set_retention(compacted.clone()).unwrap();
+        kafka.write_log(String::from("k"), Payload(json!({"id": 1, "v": "old"})), None).unwrap();
+        kafka.write_log(String::from("k"), Payload(json!({"id": 1, "v": "new"})), None).unwrap();
+        drop(kafka);
+
+        let mut kafka = reopen(dir.path());
+        kafka.set_retention(compacted).unwrap();
+
+        assert_eq!(read_from_start(&kafka, "k"), vec![(1, Payload(json!({"id": 1, "v": "new"})))]);
+    }
+}
@@ -0,0 +1,129 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot, Mutex};
+use crate::message::{KvBody, Message, MessageForm};
+
+pub const LIN_KV: &str = "lin-kv";
+pub const LWW_KV: &str = "lww-kv";
+
+// how long a request waits for the service before giving up
+const REQUEST_TIMEOUT: Duration = Duration::from_millis(1000);
+
+// maelstrom error codes the clients react to
+const KEY_DOES_NOT_EXIST: u32 = 20;
+const PRECONDITION_FAILED: u32 = 22;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum KvError {
+    Timeout,
+    KeyDoesNotExist,
+    PreconditionFailed,
+    Service {code: u32, text: String}
+}
+
+impl Display for KvError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KvError::Timeout => write!(f, "kv request timed out"),
+            KvError::KeyDoesNotExist => write!(f, "key does not exist"),
+            KvError::PreconditionFailed => write!(f, "cas precondition failed"),
+            KvError::Service {code, text} => write!(f, "kv service error {code}: {text}")
+        }
+    }
+}
+
+impl Error for KvError {}
+
+impl KvError {
+    // maelstrom error code reported to clients
+    pub fn code(&self) -> u32 {
+        match self {
+            KvError::Timeout => 11,
+            KvError::KeyDoesNotExist => KEY_DOES_NOT_EXIST,
+            KvError::PreconditionFailed => PRECONDITION_FAILED,
+            KvError::Service {code, ..} => *code
+        }
+    }
+}
+
+// client for one of maelstrom's key-value services
+// replies reach it through `resolve`, which the node calls for every message coming from the service
+#[derive(Clone)]
+pub struct KvClient {
+    service: String,
+    node_id: String,
+    out: mpsc::UnboundedSender<MessageForm>,
+    pending: Arc<Mutex<HashMap<u32, oneshot::Sender<KvBody>>>>,
+    next_msg_id: Arc<AtomicU32>
+}
+
+impl KvClient {
+
+    pub fn new(service: &str, node_id: String, out: mpsc::UnboundedSender<MessageForm>) -> Self {
+        KvClient {
+            service: String::from(service),
+            node_id,
+            out,
+            pending: Arc::new(Mutex::new(HashMap::new())),
+            next_msg_id: Arc::new(AtomicU32::new(1))
+        }
+    }
+
+    pub async fn resolve(&self, body: KvBody) {
+        let Some(in_reply_to) = body.in_reply_to() else { return };
+
+        if let Some(sender) = self.pending.lock().await.remove(&in_reply_to) {
+            let _ = sender.send(body);
+        }
+    }
+
+    async fn request(&self, make_body: impl FnOnce(u32) -> KvBody) -> Result<KvBody, KvError> {
+        let msg_id = self.next_msg_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel::<KvBody>();
+        self.pending.lock().await.insert(msg_id, tx);
+
+        let msg = Message {
+            src: String::from(&self.node_id),
+            dest: String::from(&self.service),
+            body: make_body(msg_id)
+        };
+        let _ = self.out.send(MessageForm::KvMessage(msg));
+
+        let reply = match tokio::time::timeout(REQUEST_TIMEOUT, rx).await {
+            Ok(Ok(reply)) => reply,
+            _ => {
+                self.pending.lock().await.remove(&msg_id);
+                return Err(KvError::Timeout)
+            }
+        };
+
+        match reply {
+            KvBody::Error {code: KEY_DOES_NOT_EXIST, ..} => Err(KvError::KeyDoesNotExist),
+            KvBody::Error {code: PRECONDITION_FAILED, ..} => Err(KvError::PreconditionFailed),
+            KvBody::Error {code, text, ..} => Err(KvError::Service {code, text}),
+            reply => Ok(reply)
+        }
+    }
+
+    pub async fn read(&self, key: &str) -> Result<serde_json::Value, KvError> {
+        let key = String::from(key);
+        match self.request(|msg_id| KvBody::Read {msg_id, key}).await? {
+            KvBody::ReadOk {value, ..} => Ok(value),
+            _ => Err(KvError::Service {code: 13, text: String::from("unexpected reply to read")})
+        }
+    }
+
+    pub async fn write(&self, key: &str, value: serde_json::Value) -> Result<(), KvError> {
+        let key = String::from(key);
+        self.request(|msg_id| KvBody::Write {msg_id, key, value}).await.map(|_| ())
+    }
+
+    pub async fn cas(&self, key: &str, from: serde_json::Value, to: serde_json::Value, create_if_not_exists: bool) -> Result<(), KvError> {
+        let key = String::from(key);
+        self.request(|msg_id| KvBody::Cas {msg_id, key, from, to, create_if_not_exists}).await.map(|_| ())
+    }
+}
@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+use crate::kafka::{PollLimits, ProducerSeq};
+use crate::kv::{KvClient, KvError};
+use crate::message::Payload;
+
+// where kafka logs live, picked once at startup
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum KafkaBackend {
+    // every key lives in memory of the node owning it, other nodes forward to the owner
+    #[default]
+    Owner,
+    // every node reads and writes the logs straight in lin-kv
+    LinKv
+}
+
+fn next_offset_key(log_key: &str) -> String {
+    format!("offset-{log_key}")
+}
+
+fn msg_key(log_key: &str, offset: usize) -> String {
+    format!("msg-{log_key}-{offset}")
+}
+
+// the length of the first free-form part marks where it ends, so no two pairs of parts share a key
+fn producer_seq_key(log_key: &str, producer: &ProducerSeq) -> String {
+    format!("producer-{}-{log_key}-{}-{}", log_key.len(), producer.producer_id, producer.seq)
+}
+
+fn committed_key(group: &str, log_key: &str) -> String {
+    format!("committed-{}-{group}-{log_key}", group.len())
+}
+
+// kafka logs stored in lin-kv
+// a message takes its offset by creating the key of that offset, the key's next offset only follows it
+// so every offset below the next one holds a message and readers never run into a gap
+#[derive(Clone)]
+pub(crate) struct KvKafka {
+    kv: KvClient
+}
+
+impl KvKafka {
+
+    pub fn new(kv: KvClient) -> Self {
+        KvKafka {
+            kv
+        }
+    }
+
+    async fn read_usize(&self, key: &str) -> Result<Option<usize>, KvError> {
+        match self.kv.read(key).await {
+            Ok(value) => Ok(value.as_u64().map(|v| v as usize)),
+            Err(KvError::KeyDoesNotExist) => Ok(None),
+            Err(e) => Err(e)
+        }
+    }
+
+    // a send already stored for the producer sequence returns its offset
+    // two retries racing each other may still both be appended
+    pub async fn write_log(&self, key: String, msg: Payload, producer: Option<ProducerSeq>) -> Result<usize, KvError> {
+        if let Some(producer) = &producer && let Some(offset) = self.read_usize(&producer_seq_key(&key, producer)).await? {
+            return Ok(offset)
+        }
+
+        //stored wrapped, so a taken offset never equals the null it is created from
+        let stored = serde_json::json!({"msg": msg.0});
+        let offset_key = next_offset_key(&key);
+        let offset = loop {
+            let offset = self.read_usize(&offset_key).await?.unwrap_or(0);
+            let taken = match self.kv.cas(&msg_key(&key, offset), serde_json::Value::Null, stored.clone(), true).await {
+                Ok(()) => true,
+                //someone else took this offset first
+                Err(KvError::PreconditionFailed) => false,
+                Err(e) => return Err(e)
+            };
+
+            //moves the next offset past the message, also for a writer that took it and never got this far
+            let from = match offset {
+                0 => serde_json::Value::Null,
+                offset => serde_json::Value::from(offset)
+            };
+            let moved = self.kv.cas(&offset_key, from, serde_json::Value::from(offset + 1), offset == 0).await;
+            match (taken, moved) {
+                (true, _) => break offset,
+                (false, Ok(()) | Err(KvError::PreconditionFailed)) => continue,
+                (false, Err(e)) => return Err(e)
+            }
+        };
+
+        if let Some(producer) = &producer {
+            self.kv.write(&producer_seq_key(&key, producer), serde_json::Value::from(offset)).await?;
+        }
+        Ok(offset)
+    }
+
+    // reads up to the first offset no message took yet
+    // that can be past the key's next offset, when the writer of a message never got to move it
+    async fn get_logs_from_offset(&self, log_key: &str, offset: usize, max_messages: usize) -> Result<Option<(Vec<(usize, Payload)>, bool)>, KvError> {
+        let next_offset = self.read_usize(&next_offset_key(log_key)).await?;
+
+        let mut logs = Vec::new();
+        for offset in offset..offset.saturating_add(max_messages) {
+            match self.kv.read(&msg_key(log_key, offset)).await {
+                Ok(mut value) => logs.push((offset, Payload(value["msg"].take()))),
+                Err(KvError::KeyDoesNotExist) => break,
+                Err(e) => return Err(e)
+            }
+        }
+
+        if next_offset.is_none() && logs.is_empty() {
+            return Ok(None)
+        }
+        let more = offset + logs.len() < next_offset.unwrap_or(0);
+        Ok(Some((logs, more)))
+    }
+
+    // no key is read past the per-key or total message limit, the byte limit is applied to what was read
+    pub async fn read_logs(&self, offsets: HashMap<String, usize>, limits: &PollLimits) -> Result<(HashMap<String, Vec<(usize, Payload)>>, bool), KvError> {
+        let mut logs = HashMap::new();
+        let mut more = false;
+
+        let mut offsets = offsets.into_iter().collect::<Vec<(String, usize)>>();
+        offsets.sort();
+
+        let mut left = limits.max_messages.unwrap_or(usize::MAX);
+        for (log_k, offset) in offsets {
+            let max_messages = limits.max_messages_per_key.unwrap_or(usize::MAX).min(left);
+            if let Some((logs_from_offset, key_has_more)) = self.get_logs_from_offset(&log_k, offset, max_messages).await? {
+                left -= logs_from_offset.len();
+                more |= key_has_more;
+                logs.insert(log_k, logs_from_offset);
+            }
+        }
+
+        more |= limits.trim(&mut logs);
+        Ok((logs, more))
+    }
+
+    // raised with cas only when the new offset is higher, so a delayed commit never moves it back
+    pub async fn commit_offsets(&self, group: String, offsets: HashMap<String, usize>) -> Result<(), KvError> {
+        for (log_key, offset) in offsets {
+            let key = committed_key(&group, &log_key);
+            loop {
+                let committed = self.read_usize(&key).await?;
+                if committed.is_some_and(|committed| committed >= offset) {
+                    break
+                }
+
+                let from = committed.map(serde_json::Value::from).unwrap_or(serde_json::Value::Null);
+                match self.kv.cas(&key, from, serde_json::Value::from(offset), committed.is_none()).await {
+                    Ok(()) => break,
+                    Err(KvError::PreconditionFailed) => continue,
+                    Err(e) => return Err(e)
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub async fn get_commited_offsets(&self, group: &str, log_keys: Vec<String>) -> Result<HashMap<String, usize>, KvError> {
+        let mut offsets = HashMap::new();
+
+        for log_key in log_keys {
+            if let Some(offset) = self.read_usize(&committed_key(group, &log_key)).await? {
+                offsets.insert(log_key, offset);
+            }
+        }
+
+        Ok(offsets)
+    }
+}
@@ -2,5 +2,18 @@ pub mod node;
 pub mod message;
 pub mod id_generator;
 mod counter;
-mod kafka;
+pub mod kafka;
+pub mod plumtree;
+pub mod kv;
+mod rpc;
+pub mod kv_kafka;
+pub mod log_storage;
+pub mod snapshot;
+pub mod topic;
+pub mod txn;
+mod list_append;
+pub mod mvcc;
 
+pub mod raft;
+mod raft_storage;
+mod lin_kv;
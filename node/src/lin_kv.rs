@@ -0,0 +1,154 @@
+use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use crate::kv::KvError;
+use crate::message::MessageBody;
+use crate::raft::{Raft, RaftError, StateMachine};
+use crate::rpc::{Rpc, RpcError};
+
+// a lin-kv request as it is kept in the raft log, reads go through the log too so they never see stale values
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub(crate) enum KvCommand {
+    Read {key: serde_json::Value},
+    Write {key: serde_json::Value, value: serde_json::Value},
+    Cas {key: serde_json::Value, from: serde_json::Value, to: serde_json::Value, create_if_not_exists: bool}
+}
+
+impl KvCommand {
+    // none for reads without a key, those belong to other workloads
+    pub fn from_request(body: MessageBody) -> Option<KvCommand> {
+        match body {
+            MessageBody::Read {key: Some(key), ..} => Some(KvCommand::Read {key}),
+            MessageBody::Write {key, value, ..} => Some(KvCommand::Write {key, value}),
+            MessageBody::Cas {key, from, to, create_if_not_exists, ..} => Some(KvCommand::Cas {key, from, to, create_if_not_exists}),
+            _ => None
+        }
+    }
+
+    // the client's request again, for handing it to the leader
+    pub fn to_request(&self, msg_id: u32) -> MessageBody {
+        match self.clone() {
+            KvCommand::Read {key} => MessageBody::Read {msg_id, key: Some(key)},
+            KvCommand::Write {key, value} => MessageBody::Write {msg_id, key, value},
+            KvCommand::Cas {key, from, to, create_if_not_exists} => MessageBody::Cas {msg_id, key, from, to, create_if_not_exists}
+        }
+    }
+
+    // the reply to the client's request, a follower relays it to the leader it knows of
+    pub async fn serve(&self, raft: &Raft<KvStore>, rpc: &Rpc, msg_id: u32) -> MessageBody {
+        match raft.propose(self.clone()).await {
+            Ok(result) => self.reply(msg_id, result),
+            Err(RaftError::NotLeader {leader: Some(leader)}) => match rpc.call(&leader, |id| self.to_request(id)).await {
+                Ok(mut reply) => {
+                    reply.set_in_reply_to(msg_id);
+                    reply
+                },
+                Err(RpcError::Remote {code, text}) => MessageBody::Error {in_reply_to: msg_id, code, text},
+                //the leader may have applied it before its answer got lost
+                Err(RpcError::Timeout) => MessageBody::Error {in_reply_to: msg_id, code: 13, text: format!("leader {leader} did not answer")}
+            },
+            Err(e) => MessageBody::Error {in_reply_to: msg_id, code: e.code(), text: e.to_string()}
+        }
+    }
+
+    pub fn reply(&self, in_reply_to: u32, result: Result<serde_json::Value, KvError>) -> MessageBody {
+        match (self, result) {
+            (_, Err(e)) => MessageBody::Error {in_reply_to, code: e.code(), text: e.to_string()},
+            (KvCommand::Read {..}, Ok(value)) => MessageBody::ReadOk {in_reply_to, value},
+            (KvCommand::Write {..}, Ok(_)) => MessageBody::WriteOk {in_reply_to},
+            (KvCommand::Cas {..}, Ok(_)) => MessageBody::CasOk {in_reply_to}
+        }
+    }
+}
+
+// registers of the lin-kv workload
+#[derive(Default)]
+pub(crate) struct KvStore {
+    // keys may be any json, they are kept by their serialized form
+    values: HashMap<String, serde_json::Value>
+}
+
+impl StateMachine for KvStore {
+    type Command = KvCommand;
+    // reads give the value, writes and cas give null
+    type Output = Result<serde_json::Value, KvError>;
+
+    fn apply(&mut self, command: KvCommand) -> Self::Output {
+        match command {
+            KvCommand::Read {key} => self.values.get(&key.to_string()).cloned().ok_or(KvError::KeyDoesNotExist),
+            KvCommand::Write {key, value} => {
+                self.values.insert(key.to_string(), value);
+                Ok(serde_json::Value::Null)
+            },
+            KvCommand::Cas {key, from, to, create_if_not_exists} => {
+                match self.values.get_mut(&key.to_string()) {
+                    Some(current) if *current == from => *current = to,
+                    Some(_) => return Err(KvError::PreconditionFailed),
+                    None if create_if_not_exists => {
+                        self.values.insert(key.to_string(), to);
+                    },
+                    None => return Err(KvError::KeyDoesNotExist)
+                }
+                Ok(serde_json::Value::Null)
+            }
+        }
+    }
+
+    fn snapshot(&self) -> serde_json::Value {
+        serde_json::to_value(&self.values).expect("error occur while encoding lin-kv snapshot")
+    }
+
+    fn restore(&mut self, snapshot: serde_json::Value) -> serde_json::Result<()> {
+        self.values = serde_json::from_value(snapshot)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+
+    fn cas(key: u64, from: u64, to: u64, create_if_not_exists: bool) -> KvCommand {
+        KvCommand::Cas {key: json!(key), from: json!(from), to: json!(to), create_if_not_exists}
+    }
+
+    fn read(store: &mut KvStore, key: u64) -> Result<serde_json::Value, KvError> {
+        store.apply(KvCommand::Read {key: json!(key)})
+    }
+
+    #[test]
+    fn cas_on_a_missing_key_fails_unless_it_may_create_it() {
+        let mut store = KvStore::default();
+
+        assert_eq!(store.apply(cas(1, 0, 5, false)), Err(KvError::KeyDoesNotExist));
+        assert_eq!(read(&mut store, 1), Err(KvError::KeyDoesNotExist));
+
+        assert_eq!(store.apply(cas(1, 0, 5, true)), Ok(serde_json::Value::Null));
+        assert_eq!(read(&mut store, 1), Ok(json!(5)));
+    }
+
+    #[test]
+    fn cas_swaps_only_the_expected_value() {
+        let mut store = KvStore::default();
+        store.apply(KvCommand::Write {key: json!(1), value: json!(3)}).unwrap();
+
+        assert_eq!(store.apply(cas(1, 4, 5, false)), Err(KvError::PreconditionFailed));
+        //creating is only for missing keys, a present one still has to match
+        assert_eq!(store.apply(cas(1, 4, 5, true)), Err(KvError::PreconditionFailed));
+        assert_eq!(read(&mut store, 1), Ok(json!(3)));
+
+        assert_eq!(store.apply(cas(1, 3, 5, false)), Ok(serde_json::Value::Null));
+        assert_eq!(read(&mut store, 1), Ok(json!(5)));
+    }
+
+    #[test]
+    fn keys_of_different_json_types_are_different_keys() {
+        let mut store = KvStore::default();
+        store.apply(KvCommand::Write {key: json!(1), value: json!("number")}).unwrap();
+        store.apply(KvCommand::Write {key: json!("1"), value: json!("string")}).unwrap();
+
+        assert_eq!(read(&mut store, 1), Ok(json!("number")));
+        assert_eq!(store.apply(KvCommand::Read {key: json!("1")}), Ok(json!("string")));
+    }
+}
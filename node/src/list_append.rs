@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::sync::Mutex;
+use crate::kv::{KvClient, KvError};
+use crate::txn::{MicroOp, OpKind};
+
+// lin-kv key holding the root: a map from every list key to the thunk with its current list
+const ROOT_KEY: &str = "root";
+// lww-kv is only eventually consistent, a thunk written elsewhere may take a while to show up
+const THUNK_READ_ATTEMPTS: usize = 50;
+const THUNK_RETRY_DELAY: Duration = Duration::from_millis(10);
+// maelstrom's txn-conflict, the client may retry the transaction
+const TXN_CONFLICT: u32 = 30;
+
+// serializable txn-list-append, datomic style
+// lists are immutable thunks in lww-kv, a transaction writes new thunks for the lists it appends to
+// and swaps the root in lin-kv with cas, so it commits only if nobody committed since it read the root
+#[derive(Clone)]
+pub(crate) struct ListAppend {
+    node_id: String,
+    // tells this run of the node from earlier ones, whose thunk counter started at 0 as well
+    run_id: String,
+    lin_kv: KvClient,
+    lww_kv: KvClient,
+    next_thunk: Arc<AtomicU64>,
+    // thunks never change once written, so whatever was read once is kept
+    thunks: Arc<Mutex<HashMap<String, Vec<serde_json::Value>>>>
+}
+
+impl ListAppend {
+
+    pub fn new(node_id: String, lin_kv: KvClient, lww_kv: KvClient) -> Self {
+        ListAppend {
+            node_id,
+            run_id: uuid::Uuid::new_v4().simple().to_string(),
+            lin_kv,
+            lww_kv,
+            next_thunk: Arc::new(AtomicU64::new(0)),
+            thunks: Arc::new(Mutex::new(HashMap::new()))
+        }
+    }
+
+    async fn read_root(&self) -> Result<serde_json::Value, KvError> {
+        match self.lin_kv.read(ROOT_KEY).await {
+            Ok(root) => Ok(root),
+            Err(KvError::KeyDoesNotExist) => Ok(serde_json::Value::Null),
+            Err(e) => Err(e)
+        }
+    }
+
+    async fn read_thunk(&self, id: &str) -> Result<Vec<serde_json::Value>, KvError> {
+        if let Some(list) = self.thunks.lock().await.get(id) {
+            return Ok(list.clone())
+        }
+
+        for _ in 0..THUNK_READ_ATTEMPTS {
+            match self.lww_kv.read(id).await {
+                Ok(value) => {
+                    let list = value.as_array().cloned().unwrap_or_default();
+                    self.thunks.lock().await.insert(String::from(id), list.clone());
+                    return Ok(list)
+                },
+                Err(KvError::KeyDoesNotExist) => tokio::time::sleep(THUNK_RETRY_DELAY).await,
+                Err(e) => return Err(e)
+            }
+        }
+        Err(KvError::Service {code: 11, text: format!("thunk {id} is not visible yet")})
+    }
+
+    async fn write_thunk(&self, list: Vec<serde_json::Value>) -> Result<String, KvError> {
+        let id = format!("{}-{}-{}", self.node_id, self.run_id, self.next_thunk.fetch_add(1, Ordering::Relaxed));
+        self.lww_kv.write(&id, serde_json::Value::from(list.clone())).await?;
+        self.thunks.lock().await.insert(id.clone(), list);
+        Ok(id)
+    }
+
+    // reads see the lists as of the root read, plus the transaction's own appends
+    pub async fn transact(&self, txn: Vec<MicroOp>) -> Result<Vec<MicroOp>, KvError> {
+        let root = self.read_root().await?;
+        let mut pointers = root.as_object().cloned().unwrap_or_default();
+
+        let mut lists: HashMap<u64, Vec<serde_json::Value>> = HashMap::new();
+        let mut appended = Vec::new();
+        let mut result = Vec::with_capacity(txn.len());
+        for MicroOp(kind, key, value) in txn {
+            let list = match lists.entry(key) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    let list = match pointers.get(&key.to_string()).and_then(|id| id.as_str()) {
+                        Some(id) => self.read_thunk(id).await?,
+                        None => Vec::new()
+                    };
+                    entry.insert(list)
+                }
+            };
+
+            match kind {
+                OpKind::Read => {
+                    result.push(MicroOp(kind, key, serde_json::Value::from(list.clone())));
+                },
+                OpKind::Append => {
+                    list.push(value.clone());
+                    appended.push(key);
+                    result.push(MicroOp(kind, key, value));
+                },
+                OpKind::Write => {
+                    return Err(KvError::Service {code: 10, text: String::from("list-append transactions only read and append")})
+                }
+            }
+        }
+
+        //read-only transactions saw one root, nothing to commit
+        if appended.is_empty() {
+            return Ok(result)
+        }
+
+        appended.sort();
+        appended.dedup();
+        for key in appended {
+            let id = self.write_thunk(lists.remove(&key).unwrap()).await?;
+            pointers.insert(key.to_string(), serde_json::Value::from(id));
+        }
+
+        let create = root.is_null();
+        match self.lin_kv.cas(ROOT_KEY, root, serde_json::Value::from(pointers), create).await {
+            Ok(()) => Ok(result),
+            Err(KvError::PreconditionFailed) => Err(KvError::Service {code: TXN_CONFLICT, text: String::from("root changed while the transaction ran")}),
+            //the swap may have happened, so the outcome is unknown rather than failed
+            Err(KvError::Timeout) => Err(KvError::Service {code: 13, text: String::from("root swap timed out")}),
+            Err(e) => Err(e)
+        }
+    }
+}
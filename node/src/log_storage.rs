@@ -0,0 +1,382 @@
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde::Serialize;
+use crate::message::Payload;
+
+// a segment is closed and a new one started once it grows past this size
+const SEGMENT_MAX_BYTES: u64 = 1024 * 1024;
+// record header: payload length and crc32 as little endian u32, then the append time in unix millis as little endian u64
+// the crc covers the append time and the payload
+const RECORD_HEADER_BYTES: usize = 16;
+const COMMITTED_FILE: &str = "committed.json";
+// kept in a key's directory once the key is truncated, messages below the offset in it are dropped on recovery
+const START_OFFSET_FILE: &str = "start_offset.json";
+
+// when appended records are forced to disk
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    // after every append, nothing acknowledged is ever lost
+    Always,
+    // every interval for all segments appended to meanwhile, a crash loses at most the last interval's records
+    Interval(Duration),
+    // left to the operating system
+    Never
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+// keys are arbitrary strings, so their directories are named by the hex of their bytes
+fn key_dir_name(key: &str) -> String {
+    key.bytes().map(|b| format!("{b:02x}")).collect()
+}
+
+fn key_from_dir_name(name: &str) -> Option<String> {
+    if !name.len().is_multiple_of(2) {
+        return None
+    }
+    let bytes = (0..name.len()).step_by(2).map(|i| u8::from_str_radix(&name[i..i + 2], 16).ok()).collect::<Option<Vec<u8>>>()?;
+    String::from_utf8(bytes).ok()
+}
+
+fn segment_path(key_dir: &Path, base_offset: usize) -> PathBuf {
+    key_dir.join(format!("{base_offset:020}.log"))
+}
+
+// a new file, a rename or a new directory is only durable once the directory holding it is synced
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+// a stored message and when it was appended
+type StoredRecord = (Payload, SystemTime);
+
+// records are found by scanning, reads are served from memory and recovery checks every record anyway
+struct Segment {
+    base_offset: usize,
+    log: File,
+    size: u64,
+    records: usize
+}
+
+impl Segment {
+    fn create(key_dir: &Path, base_offset: usize) -> io::Result<Self> {
+        let log = OpenOptions::new().create(true).read(true).append(true).open(segment_path(key_dir, base_offset))?;
+        Ok(Segment {
+            base_offset,
+            log,
+            size: 0,
+            records: 0
+        })
+    }
+
+    // reads every intact record and cuts the log after the last one
+    fn recover(key_dir: &Path, base_offset: usize) -> io::Result<(Self, Vec<StoredRecord>, bool)> {
+        let mut log = OpenOptions::new().read(true).append(true).open(segment_path(key_dir, base_offset))?;
+
+        let mut content = Vec::new();
+        log.read_to_end(&mut content)?;
+
+        let mut payloads = Vec::new();
+        let mut pos = 0usize;
+        while pos + RECORD_HEADER_BYTES <= content.len() {
+            let len = u32::from_le_bytes(content[pos..pos + 4].try_into().unwrap()) as usize;
+            let crc = u32::from_le_bytes(content[pos + 4..pos + 8].try_into().unwrap());
+            let start = pos + RECORD_HEADER_BYTES;
+            //the crc covers everything after itself
+            let Some(checked) = content.get(pos + 8..start + len) else { break };
+            if crc32(checked) != crc {
+                break
+            }
+            let Ok(payload) = serde_json::from_slice::<Payload>(&checked[8..]) else { break };
+            let millis = u64::from_le_bytes(checked[..8].try_into().unwrap());
+
+            payloads.push((payload, UNIX_EPOCH + Duration::from_millis(millis)));
+            pos = start + len;
+        }
+
+        let torn = pos < content.len();
+        if torn {
+            log.set_len(pos as u64)?;
+            log.sync_all()?;
+        }
+
+        let segment = Segment {
+            base_offset,
+            log,
+            size: pos as u64,
+            records: payloads.len()
+        };
+        Ok((segment, payloads, torn))
+    }
+
+    fn append(&mut self, payload: &Payload, appended_at: SystemTime) -> io::Result<()> {
+        let bytes = serde_json::to_vec(payload)?;
+        let millis = appended_at.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+        let mut record = Vec::with_capacity(RECORD_HEADER_BYTES + bytes.len());
+        record.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        record.extend_from_slice(&[0; 4]);
+        record.extend_from_slice(&millis.to_le_bytes());
+        record.extend_from_slice(&bytes);
+        let crc = crc32(&record[8..]);
+        record[4..8].copy_from_slice(&crc.to_le_bytes());
+
+        self.log.write_all(&record)?;
+        self.size += record.len() as u64;
+        self.records += 1;
+        Ok(())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.log.sync_data()
+    }
+
+    // handle to the segment's file, for syncing it off the node's loop
+    fn try_clone_file(&self) -> io::Result<File> {
+        self.log.try_clone()
+    }
+
+    fn end_offset(&self) -> usize {
+        self.base_offset + self.records
+    }
+
+    fn remove(key_dir: &Path, base_offset: usize) -> io::Result<()> {
+        fs::remove_file(segment_path(key_dir, base_offset))
+    }
+}
+
+// segments appended to since the last interval sync: key dir & base offset -> log file
+type DirtySegments = Mutex<HashMap<(PathBuf, usize), File>>;
+
+// syncs the dirty segments every `interval` until the storage is dropped
+fn spawn_interval_sync(dirty: Weak<DirtySegments>, interval: Duration) {
+    std::thread::spawn(move || loop {
+        std::thread::sleep(interval);
+        let Some(dirty) = dirty.upgrade() else { break };
+        let segments = std::mem::take(&mut *dirty.lock().expect("error occur while taking dirty segments"));
+        drop(dirty);
+
+        for ((key_dir, base_offset), log) in segments {
+            if let Err(e) = log.sync_data() {
+                eprintln!("error occur while syncing segment {base_offset} of {}: {e}", key_dir.display());
+            }
+        }
+    });
+}
+
+struct KeyLog {
+    dir: PathBuf,
+    // ordered by base offset, the last one is appended to
+    segments: Vec<Segment>
+}
+
+// append-only segment files for every kafka key plus the committed offsets
+pub(crate) struct LogStorage {
+    dir: PathBuf,
+    fsync: FsyncPolicy,
+    dirty: Arc<DirtySegments>,
+    keys: HashMap<String, KeyLog>
+}
+
+// what was found on disk while opening the storage
+pub(crate) struct Recovered {
+    // log_key -> offset of the first stored message & messages from there on with their append times
+    pub logs: HashMap<String, (usize, Vec<StoredRecord>)>,
+    pub committed: HashMap<String, HashMap<String, usize>>
+}
+
+impl LogStorage {
+
+    pub fn open(dir: &Path, fsync: FsyncPolicy) -> io::Result<(Self, Recovered)> {
+        fs::create_dir_all(dir)?;
+
+        let mut keys = HashMap::new();
+        let mut logs = HashMap::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                continue
+            }
+            let Some(key) = entry.file_name().to_str().and_then(key_from_dir_name) else { continue };
+
+            let (key_log, payloads) = LogStorage::recover_key(entry.path())?;
+            keys.insert(key.clone(), key_log);
+            logs.insert(key, payloads);
+        }
+
+        let committed = match fs::read(dir.join(COMMITTED_FILE)) {
+            Ok(bytes) => serde_json::from_slice(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e)
+        };
+
+        let dirty = Arc::new(Mutex::new(HashMap::new()));
+        if let FsyncPolicy::Interval(interval) = fsync {
+            spawn_interval_sync(Arc::downgrade(&dirty), interval);
+        }
+
+        let storage = LogStorage {
+            dir: dir.to_path_buf(),
+            fsync,
+            dirty,
+            keys
+        };
+        Ok((storage, Recovered {logs, committed}))
+    }
+
+    fn recover_key(key_dir: PathBuf) -> io::Result<(KeyLog, (usize, Vec<StoredRecord>))> {
+        let mut base_offsets = fs::read_dir(&key_dir)?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let name = entry.file_name().into_string().ok()?;
+                name.strip_suffix(".log")?.parse::<usize>().ok()
+            })
+            .collect::<Vec<usize>>();
+        base_offsets.sort();
+
+        //segments below the first one were dropped by retention
+        let first_offset = base_offsets.first().copied().unwrap_or(0);
+        let mut segments: Vec<Segment> = Vec::new();
+        let mut payloads = Vec::new();
+        let mut base_offsets = base_offsets.into_iter();
+        for base_offset in base_offsets.by_ref() {
+            //a segment not starting where the previous one ended can't be trusted
+            if segments.last().is_some_and(|last| last.end_offset() != base_offset) {
+                Segment::remove(&key_dir, base_offset)?;
+                continue
+            }
+
+            let (segment, segment_payloads, torn) = Segment::recover(&key_dir, base_offset)?;
+            payloads.extend(segment_payloads);
+            segments.push(segment);
+            if torn {
+                break
+            }
+        }
+
+        //everything after a torn write was never acknowledged
+        for base_offset in base_offsets {
+            Segment::remove(&key_dir, base_offset)?;
+        }
+
+        //a truncation may have ended inside the first segment
+        let truncated_at = match fs::read(key_dir.join(START_OFFSET_FILE)) {
+            Ok(bytes) => serde_json::from_slice::<usize>(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => first_offset,
+            Err(e) => return Err(e)
+        };
+        let dropped = truncated_at.saturating_sub(first_offset).min(payloads.len());
+        payloads.drain(..dropped);
+
+        Ok((KeyLog {dir: key_dir, segments}, (first_offset + dropped, payloads)))
+    }
+
+    // removes segments holding only offsets below `offset`
+    // the segment being appended to always stays, it remembers where the key's offsets continue
+    pub fn drop_before(&mut self, key: &str, offset: usize) -> io::Result<()> {
+        let Some(key_log) = self.keys.get_mut(key) else { return Ok(()) };
+
+        while key_log.segments.len() > 1 && key_log.segments[0].end_offset() <= offset {
+            let segment = key_log.segments.remove(0);
+            Segment::remove(&key_log.dir, segment.base_offset)?;
+        }
+        Ok(())
+    }
+
+    // swapped in like the committed offsets, a crash leaves either the old or the new start
+    pub fn save_start_offset(&mut self, key: &str, offset: usize) -> io::Result<()> {
+        let key_log = LogStorage::key_log(&mut self.keys, &self.dir, key)?;
+        write_synced(&key_log.dir, START_OFFSET_FILE, &offset, self.fsync)
+    }
+
+    // takes the fields it needs, so the fsync state stays usable while the key's log is borrowed
+    fn key_log<'a>(keys: &'a mut HashMap<String, KeyLog>, dir: &Path, key: &str) -> io::Result<&'a mut KeyLog> {
+        if !keys.contains_key(key) {
+            let key_dir = dir.join(key_dir_name(key));
+            fs::create_dir_all(&key_dir)?;
+            sync_dir(dir)?;
+            keys.insert(String::from(key), KeyLog {dir: key_dir, segments: Vec::new()});
+        }
+        Ok(keys.get_mut(key).unwrap())
+    }
+
+    // makes a key without stored messages continue at `offset`, its empty first segment remembers where
+    pub fn start_key_at(&mut self, key: &str, offset: usize) -> io::Result<()> {
+        let key_log = LogStorage::key_log(&mut self.keys, &self.dir, key)?;
+        if key_log.segments.is_empty() {
+            let segment = Segment::create(&key_log.dir, offset)?;
+            segment.sync()?;
+            sync_dir(&key_log.dir)?;
+            key_log.segments.push(segment);
+        }
+        Ok(())
+    }
+
+    // `offset` is the offset kafka assigned to `msg`, it always follows the last stored one
+    pub fn append(&mut self, key: &str, offset: usize, msg: &Payload, appended_at: SystemTime) -> io::Result<()> {
+        let key_log = LogStorage::key_log(&mut self.keys, &self.dir, key)?;
+
+        let needs_segment = key_log.segments.last().is_none_or(|segment| segment.size >= SEGMENT_MAX_BYTES);
+        if needs_segment {
+            if let Some(full) = key_log.segments.last() {
+                full.sync()?;
+            }
+            key_log.segments.push(Segment::create(&key_log.dir, offset)?);
+            //records of a segment whose file isn't in its directory yet would be lost with it
+            if self.fsync != FsyncPolicy::Never {
+                sync_dir(&key_log.dir)?;
+            }
+        }
+
+        let segment = key_log.segments.last_mut().unwrap();
+        segment.append(msg, appended_at)?;
+
+        match self.fsync {
+            FsyncPolicy::Always => segment.sync()?,
+            FsyncPolicy::Interval(_) => {
+                let mut dirty = self.dirty.lock().expect("error occur while marking segment dirty");
+                if let Entry::Vacant(entry) = dirty.entry((key_log.dir.clone(), segment.base_offset)) {
+                    entry.insert(segment.try_clone_file()?);
+                }
+            },
+            FsyncPolicy::Never => {}
+        }
+
+        Ok(())
+    }
+
+    // rewritten as a whole and swapped in, so a crash leaves either the old or the new offsets
+    pub fn save_committed(&self, committed: &HashMap<String, HashMap<String, usize>>) -> io::Result<()> {
+        write_synced(&self.dir, COMMITTED_FILE, committed, self.fsync)
+    }
+}
+
+// writes `value` as json next to `name` and renames it into place, synced unless the policy says never
+fn write_synced(dir: &Path, name: &str, value: &impl Serialize, fsync: FsyncPolicy) -> io::Result<()> {
+    let path = dir.join(name);
+    let tmp_path = path.with_extension("tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(&serde_json::to_vec(value)?)?;
+    if fsync != FsyncPolicy::Never {
+        file.sync_all()?;
+    }
+    fs::rename(tmp_path, path)?;
+    if fsync != FsyncPolicy::Never {
+        sync_dir(dir)?;
+    }
+    Ok(())
+}
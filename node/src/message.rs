@@ -1,6 +1,11 @@
 use std::fmt::Debug;
-use serde::{Serialize, Deserialize};
+use std::cmp::Ordering;
+use std::hash::{Hash, Hasher};
+use serde::{Serialize, Deserialize, Deserializer};
 use std::collections::HashMap;
+use crate::kafka::KeyStats;
+use crate::raft::LogEntry;
+use crate::txn::{MicroOp, ReplicatedTxn};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Message<Body> {
@@ -21,6 +26,9 @@ impl Message<MessageBody> {
             MessageBody::GenerateOk {..} => String::from("generate_ok"),
             MessageBody::Broadcast {..} => String::from("broadcast"),
             MessageBody::BroadcastOk {..} => String::from("broadcast_ok"),
+            MessageBody::IHave {..} => String::from("i_have"),
+            MessageBody::Graft {..} => String::from("graft"),
+            MessageBody::Prune {..} => String::from("prune"),
             MessageBody::Read {..} => String::from("read"),
             MessageBody::ReadOk {..} => String::from("read_ok"),
             MessageBody::Topology {..} => String::from("topology"),
@@ -30,12 +38,55 @@ impl Message<MessageBody> {
             MessageBody::ShareCounterState {..} => String::from("share_counter_state"),
             MessageBody::Send {..} => String::from("send"),
             MessageBody::SendOk {..} => String::from("send_ok"),
+            MessageBody::SendBatch {..} => String::from("send_batch"),
+            MessageBody::SendBatchOk {..} => String::from("send_batch_ok"),
+            MessageBody::PrepareBatch {..} => String::from("prepare_batch"),
+            MessageBody::PrepareBatchOk {..} => String::from("prepare_batch_ok"),
+            MessageBody::CommitBatch {..} => String::from("commit_batch"),
+            MessageBody::CommitBatchOk {..} => String::from("commit_batch_ok"),
+            MessageBody::AbortBatch {..} => String::from("abort_batch"),
+            MessageBody::AbortBatchOk {..} => String::from("abort_batch_ok"),
+            MessageBody::BatchOutcome {..} => String::from("batch_outcome"),
+            MessageBody::BatchOutcomeOk {..} => String::from("batch_outcome_ok"),
             MessageBody::Poll {..} => String::from("poll"),
             MessageBody::PollOk {..} => String::from("poll_ok"),
             MessageBody::CommitOffsets {..} => String::from("commit_offsets"),
             MessageBody::CommitOffsetsOk {..} => String::from("commit_offsets_ok"),
             MessageBody::ListCommittedOffsets {..} => String::from("list_committed_offsets"),
             MessageBody::ListCommittedOffsetsOk {..} => String::from("list_committed_offsets_ok"),
+            MessageBody::ListKeys {..} => String::from("list_keys"),
+            MessageBody::ListKeysOk {..} => String::from("list_keys_ok"),
+            MessageBody::DescribeKeys {..} => String::from("describe_keys"),
+            MessageBody::DescribeKeysOk {..} => String::from("describe_keys_ok"),
+            MessageBody::ListGroups {..} => String::from("list_groups"),
+            MessageBody::ListGroupsOk {..} => String::from("list_groups_ok"),
+            MessageBody::TruncateKey {..} => String::from("truncate_key"),
+            MessageBody::TruncateKeyOk {..} => String::from("truncate_key_ok"),
+            MessageBody::ExportSnapshot {..} => String::from("export_snapshot"),
+            MessageBody::ExportSnapshotOk {..} => String::from("export_snapshot_ok"),
+            MessageBody::TopicSend {..} => String::from("topic_send"),
+            MessageBody::TopicSendOk {..} => String::from("topic_send_ok"),
+            MessageBody::TopicPoll {..} => String::from("topic_poll"),
+            MessageBody::TopicPollOk {..} => String::from("topic_poll_ok"),
+            MessageBody::TopicCommitOffsets {..} => String::from("topic_commit_offsets"),
+            MessageBody::TopicCommitOffsetsOk {..} => String::from("topic_commit_offsets_ok"),
+            MessageBody::TopicListCommittedOffsets {..} => String::from("topic_list_committed_offsets"),
+            MessageBody::TopicListCommittedOffsetsOk {..} => String::from("topic_list_committed_offsets_ok"),
+            MessageBody::Txn {..} => String::from("txn"),
+            MessageBody::TxnOk {..} => String::from("txn_ok"),
+            MessageBody::ReplicateTxn {..} => String::from("replicate_txn"),
+            MessageBody::ReplicateTxnOk {..} => String::from("replicate_txn_ok"),
+            MessageBody::Write {..} => String::from("write"),
+            MessageBody::WriteOk {..} => String::from("write_ok"),
+            MessageBody::Cas {..} => String::from("cas"),
+            MessageBody::CasOk {..} => String::from("cas_ok"),
+            MessageBody::RequestVote {..} => String::from("request_vote"),
+            MessageBody::RequestVoteOk {..} => String::from("request_vote_ok"),
+            MessageBody::AppendEntries {..} => String::from("append_entries"),
+            MessageBody::AppendEntriesOk {..} => String::from("append_entries_ok"),
+            MessageBody::InstallSnapshot {..} => String::from("install_snapshot"),
+            MessageBody::InstallSnapshotOk {..} => String::from("install_snapshot_ok"),
+            MessageBody::Error {..} => String::from("error"),
         }
     }
 
@@ -65,11 +116,59 @@ impl Message<MessageBody> {
             },
             MessageBody::Topology {msg_id, ..} => {
                 Some(msg_id)
+            },
+            MessageBody::Send {msg_id, ..}
+            | MessageBody::SendBatch {msg_id, ..}
+            | MessageBody::Poll {msg_id, ..}
+            | MessageBody::CommitOffsets {msg_id, ..}
+            | MessageBody::ListCommittedOffsets {msg_id, ..}
+            | MessageBody::DescribeKeys {msg_id, ..}
+            | MessageBody::TruncateKey {msg_id, ..} => {
+                Some(msg_id)
             }
             _ => None
         }
     }
 
+    pub fn in_reply_to(&self) -> Option<u32> {
+        match &self.body {
+            MessageBody::InitOk {in_reply_to, ..}
+            | MessageBody::EchoOk {in_reply_to, ..}
+            | MessageBody::GenerateOk {in_reply_to, ..}
+            | MessageBody::BroadcastOk {in_reply_to, ..}
+            | MessageBody::TopologyOk {in_reply_to, ..}
+            | MessageBody::AddOk {in_reply_to, ..}
+            | MessageBody::ReadOk {in_reply_to, ..}
+            | MessageBody::SendOk {in_reply_to, ..}
+            | MessageBody::SendBatchOk {in_reply_to, ..}
+            | MessageBody::PrepareBatchOk {in_reply_to, ..}
+            | MessageBody::CommitBatchOk {in_reply_to, ..}
+            | MessageBody::AbortBatchOk {in_reply_to, ..}
+            | MessageBody::BatchOutcomeOk {in_reply_to, ..}
+            | MessageBody::PollOk {in_reply_to, ..}
+            | MessageBody::CommitOffsetsOk {in_reply_to, ..}
+            | MessageBody::ListCommittedOffsetsOk {in_reply_to, ..}
+            | MessageBody::ListKeysOk {in_reply_to, ..}
+            | MessageBody::DescribeKeysOk {in_reply_to, ..}
+            | MessageBody::ListGroupsOk {in_reply_to, ..}
+            | MessageBody::TruncateKeyOk {in_reply_to, ..}
+            | MessageBody::ExportSnapshotOk {in_reply_to, ..}
+            | MessageBody::TopicSendOk {in_reply_to, ..}
+            | MessageBody::TopicPollOk {in_reply_to, ..}
+            | MessageBody::TopicCommitOffsetsOk {in_reply_to, ..}
+            | MessageBody::TopicListCommittedOffsetsOk {in_reply_to, ..}
+            | MessageBody::TxnOk {in_reply_to, ..}
+            | MessageBody::ReplicateTxnOk {in_reply_to, ..}
+            | MessageBody::WriteOk {in_reply_to, ..}
+            | MessageBody::CasOk {in_reply_to, ..}
+            | MessageBody::RequestVoteOk {in_reply_to, ..}
+            | MessageBody::AppendEntriesOk {in_reply_to, ..}
+            | MessageBody::InstallSnapshotOk {in_reply_to, ..}
+            | MessageBody::Error {in_reply_to, ..} => Some(*in_reply_to),
+            _ => None
+        }
+    }
+
     pub fn echo(&self) -> Option<&String> {
         match &self.body {
             MessageBody::Echo {echo, ..} => {
@@ -98,9 +197,9 @@ impl Message<MessageBody> {
     }
 }
 
-impl Into<MessageForm> for Message<MessageBody> {
-    fn into(self) -> MessageForm {
-        MessageForm::NodeMessage(self)
+impl From<Message<MessageBody>> for MessageForm {
+    fn from(msg: Message<MessageBody>) -> Self {
+        MessageForm::NodeMessage(msg)
     }
 }
 
@@ -112,28 +211,313 @@ pub enum MessageBody {
     InitOk {in_reply_to: u32},
     EchoOk {msg_id: u32, in_reply_to: u32, echo: String},
     Generate {msg_id: u32},
-    GenerateOk {msg_id: u32, id: u32, in_reply_to: u32},
-    Broadcast {msg_id: u32, message: u32},
+    GenerateOk {msg_id: u32, id: serde_json::Value, in_reply_to: u32},
+    Broadcast {msg_id: u32, message: Payload},
     BroadcastOk {in_reply_to: u32},
+    IHave {messages: Vec<Payload>},
+    Graft {messages: Vec<Payload>},
+    Prune {},
     Topology {msg_id: u32, topology: HashMap<String, Vec<String>>},
     TopologyOk {in_reply_to: u32},
     Add {msg_id: u32, delta: i32},
     AddOk {in_reply_to: u32},
-    Read {msg_id: u32},
-    ReadOk {in_reply_to: u32, value: i32},
+    // lin-kv reads carry a `key`, counter and broadcast reads don't
+    Read {msg_id: u32, #[serde(default, skip_serializing_if = "Option::is_none")] key: Option<serde_json::Value>},
+    ReadOk {in_reply_to: u32, value: serde_json::Value},
     ShareCounterState {value: i32},
-    Send {key: String, msg: u32, msg_id: u32},
+    // producers that retry set `producer_id` and `seq`, so a retry is recognized as the same send
+    Send {
+        key: String,
+        msg: Payload,
+        msg_id: u32,
+        #[serde(default, skip_serializing_if = "Option::is_none")] producer_id: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")] seq: Option<u64>
+    },
     SendOk {offset: usize, in_reply_to: u32},
-    Poll {offsets: HashMap<String, usize>, msg_id: u32},
-    PollOk {in_reply_to: u32, msgs: HashMap<String, Vec<(usize, u32)>>},
-    CommitOffsets {msg_id: u32, offsets: HashMap<String, usize>},
+    // appends one message to each key, either all of them get an offset or none
+    SendBatch {msg_id: u32, msgs: HashMap<String, Payload>},
+    SendBatchOk {in_reply_to: u32, offsets: HashMap<String, usize>},
+    // two-phase commit of a batch between its coordinator and the owners of its keys
+    PrepareBatch {msg_id: u32, txn_id: String, msgs: HashMap<String, Payload>},
+    PrepareBatchOk {in_reply_to: u32, offsets: HashMap<String, usize>},
+    CommitBatch {msg_id: u32, txn_id: String},
+    CommitBatchOk {in_reply_to: u32},
+    AbortBatch {msg_id: u32, txn_id: String},
+    AbortBatchOk {in_reply_to: u32},
+    // an owner holding a prepared batch too long asks its coordinator, a batch not decided yet is aborted
+    BatchOutcome {msg_id: u32, txn_id: String},
+    BatchOutcomeOk {in_reply_to: u32, commit: bool},
+    // optional limits, the node's defaults apply to the ones left out
+    Poll {
+        offsets: HashMap<String, usize>,
+        msg_id: u32,
+        #[serde(default, skip_serializing_if = "Option::is_none")] max_messages_per_key: Option<usize>,
+        #[serde(default, skip_serializing_if = "Option::is_none")] max_messages: Option<usize>,
+        #[serde(default, skip_serializing_if = "Option::is_none")] max_bytes: Option<usize>
+    },
+    // `more` is set when some key has messages past the returned ones
+    PollOk {in_reply_to: u32, msgs: HashMap<String, Vec<(usize, Payload)>>, #[serde(default, skip_serializing_if = "std::ops::Not::not")] more: bool},
+    // offsets of consumers without a `group` go to the default group
+    CommitOffsets {msg_id: u32, offsets: HashMap<String, usize>, #[serde(default, skip_serializing_if = "Option::is_none")] group: Option<String>},
     CommitOffsetsOk {in_reply_to: u32},
-    ListCommittedOffsets {keys: Vec<String>, msg_id: u32},
-    ListCommittedOffsetsOk {in_reply_to: u32, offsets: HashMap<String, usize>}
+    ListCommittedOffsets {keys: Vec<String>, msg_id: u32, #[serde(default, skip_serializing_if = "Option::is_none")] group: Option<String>},
+    ListCommittedOffsetsOk {in_reply_to: u32, offsets: HashMap<String, usize>},
+    // kafka admin, a node asked by a client gathers the answer from every key owner
+    ListKeys {msg_id: u32},
+    ListKeysOk {in_reply_to: u32, keys: Vec<String>},
+    DescribeKeys {msg_id: u32, keys: Vec<String>},
+    DescribeKeysOk {in_reply_to: u32, keys: HashMap<String, KeyStats>},
+    // lag is the number of messages after a group's committed offset, per key
+    ListGroups {msg_id: u32},
+    ListGroupsOk {in_reply_to: u32, groups: HashMap<String, HashMap<String, usize>>},
+    // drops the key's messages below `offset`
+    TruncateKey {msg_id: u32, key: String, offset: usize},
+    TruncateKeyOk {in_reply_to: u32},
+    // writes the node's own kafka logs and committed offsets to a json lines file at `path` inside its snapshot dir
+    ExportSnapshot {msg_id: u32, path: String},
+    ExportSnapshotOk {in_reply_to: u32},
+    // partitioned topics, offsets are per partition: topic -> partition -> offset
+    // `partition` picks one explicitly, otherwise the topic's partitioner does, hashing `key` when set
+    TopicSend {
+        msg_id: u32,
+        topic: String,
+        msg: Payload,
+        #[serde(default, skip_serializing_if = "Option::is_none")] key: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")] partition: Option<usize>
+    },
+    TopicSendOk {in_reply_to: u32, partition: usize, offset: usize},
+    // `max_messages_per_key` caps every partition
+    TopicPoll {
+        #[serde(deserialize_with = "partition_map")] offsets: HashMap<String, HashMap<usize, usize>>,
+        msg_id: u32,
+        #[serde(default, skip_serializing_if = "Option::is_none")] max_messages_per_key: Option<usize>,
+        #[serde(default, skip_serializing_if = "Option::is_none")] max_messages: Option<usize>,
+        #[serde(default, skip_serializing_if = "Option::is_none")] max_bytes: Option<usize>
+    },
+    TopicPollOk {
+        in_reply_to: u32,
+        #[serde(deserialize_with = "partition_map")] msgs: HashMap<String, HashMap<usize, Vec<(usize, Payload)>>>,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")] more: bool
+    },
+    TopicCommitOffsets {msg_id: u32, #[serde(deserialize_with = "partition_map")] offsets: HashMap<String, HashMap<usize, usize>>, #[serde(default, skip_serializing_if = "Option::is_none")] group: Option<String>},
+    TopicCommitOffsetsOk {in_reply_to: u32},
+    // lists the committed offsets of every partition of `topics`
+    TopicListCommittedOffsets {topics: Vec<String>, msg_id: u32, #[serde(default, skip_serializing_if = "Option::is_none")] group: Option<String>},
+    TopicListCommittedOffsetsOk {in_reply_to: u32, #[serde(deserialize_with = "partition_map")] offsets: HashMap<String, HashMap<usize, usize>>},
+    Txn {msg_id: u32, txn: Vec<MicroOp>},
+    TxnOk {in_reply_to: u32, txn: Vec<MicroOp>},
+    // transactions applied on another node, in the order it applied them, each with its writes in the order it made them
+    ReplicateTxn {msg_id: u32, txns: Vec<ReplicatedTxn>},
+    ReplicateTxnOk {in_reply_to: u32},
+    // lin-kv, served through raft
+    Write {msg_id: u32, key: serde_json::Value, value: serde_json::Value},
+    WriteOk {in_reply_to: u32},
+    Cas {
+        msg_id: u32,
+        key: serde_json::Value,
+        from: serde_json::Value,
+        to: serde_json::Value,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")] create_if_not_exists: bool
+    },
+    CasOk {in_reply_to: u32},
+    // raft between nodes
+    RequestVote {msg_id: u32, term: u64, candidate_id: String, last_log_index: u64, last_log_term: u64},
+    RequestVoteOk {in_reply_to: u32, term: u64, vote_granted: bool},
+    // `match_index` is the last entry the follower now shares with the leader, or where to try again when `success` is false
+    AppendEntries {
+        msg_id: u32,
+        term: u64,
+        leader_id: String,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<LogEntry>,
+        leader_commit: u64
+    },
+    AppendEntriesOk {in_reply_to: u32, term: u64, success: bool, match_index: u64},
+    // the leader's state machine for a follower missing entries the leader already compacted away
+    // it goes in one piece, the workloads served here keep small state
+    InstallSnapshot {msg_id: u32, term: u64, leader_id: String, last_included_index: u64, last_included_term: u64, data: serde_json::Value},
+    InstallSnapshotOk {in_reply_to: u32, term: u64},
+    Error {in_reply_to: u32, code: u32, text: String}
+}
+
+// partitions are json object keys, so strings, and internally tagged bodies can't turn those into numbers on their own
+fn partition_map<'de, D, V>(deserializer: D) -> Result<HashMap<String, HashMap<usize, V>>, D::Error>
+where
+    D: Deserializer<'de>,
+    V: Deserialize<'de>
+{
+    let by_topic = HashMap::<String, HashMap<String, V>>::deserialize(deserializer)?;
+    by_topic.into_iter().map(|(topic, by_partition)| {
+        let by_partition = by_partition.into_iter()
+            .map(|(partition, value)| partition.parse::<usize>().map(|partition| (partition, value)).map_err(serde::de::Error::custom))
+            .collect::<Result<HashMap<usize, V>, D::Error>>()?;
+        Ok((topic, by_partition))
+    }).collect()
+}
+
+impl MessageBody {
+    // re-addresses a reply, used when relaying a peer's answer back to the client
+    pub fn set_in_reply_to(&mut self, id: u32) {
+        match self {
+            MessageBody::InitOk {in_reply_to, ..}
+            | MessageBody::EchoOk {in_reply_to, ..}
+            | MessageBody::GenerateOk {in_reply_to, ..}
+            | MessageBody::BroadcastOk {in_reply_to, ..}
+            | MessageBody::TopologyOk {in_reply_to, ..}
+            | MessageBody::AddOk {in_reply_to, ..}
+            | MessageBody::ReadOk {in_reply_to, ..}
+            | MessageBody::SendOk {in_reply_to, ..}
+            | MessageBody::SendBatchOk {in_reply_to, ..}
+            | MessageBody::PrepareBatchOk {in_reply_to, ..}
+            | MessageBody::CommitBatchOk {in_reply_to, ..}
+            | MessageBody::AbortBatchOk {in_reply_to, ..}
+            | MessageBody::BatchOutcomeOk {in_reply_to, ..}
+            | MessageBody::PollOk {in_reply_to, ..}
+            | MessageBody::CommitOffsetsOk {in_reply_to, ..}
+            | MessageBody::ListCommittedOffsetsOk {in_reply_to, ..}
+            | MessageBody::ListKeysOk {in_reply_to, ..}
+            | MessageBody::DescribeKeysOk {in_reply_to, ..}
+            | MessageBody::ListGroupsOk {in_reply_to, ..}
+            | MessageBody::TruncateKeyOk {in_reply_to, ..}
+            | MessageBody::ExportSnapshotOk {in_reply_to, ..}
+            | MessageBody::TopicSendOk {in_reply_to, ..}
+            | MessageBody::TopicPollOk {in_reply_to, ..}
+            | MessageBody::TopicCommitOffsetsOk {in_reply_to, ..}
+            | MessageBody::TopicListCommittedOffsetsOk {in_reply_to, ..}
+            | MessageBody::TxnOk {in_reply_to, ..}
+            | MessageBody::ReplicateTxnOk {in_reply_to, ..}
+            | MessageBody::WriteOk {in_reply_to, ..}
+            | MessageBody::CasOk {in_reply_to, ..}
+            | MessageBody::RequestVoteOk {in_reply_to, ..}
+            | MessageBody::AppendEntriesOk {in_reply_to, ..}
+            | MessageBody::InstallSnapshotOk {in_reply_to, ..}
+            | MessageBody::Error {in_reply_to, ..} => *in_reply_to = id,
+            _ => {}
+        }
+    }
+
+    // the kafka keys a request names
+    pub fn kafka_keys(&self) -> Vec<&String> {
+        match self {
+            MessageBody::Send {key, ..} | MessageBody::TruncateKey {key, ..} => vec![key],
+            MessageBody::SendBatch {msgs, ..} => msgs.keys().collect(),
+            MessageBody::Poll {offsets, ..} | MessageBody::CommitOffsets {offsets, ..} => offsets.keys().collect(),
+            MessageBody::ListCommittedOffsets {keys, ..} | MessageBody::DescribeKeys {keys, ..} => keys.iter().collect(),
+            _ => Vec::new()
+        }
+    }
+}
+
+// services whose messages are parsed as `KvBody`
+pub const KV_SERVICES: [&str; 3] = ["lin-kv", "seq-kv", "lww-kv"];
+
+// requests to and replies from maelstrom's key-value services
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum KvBody {
+    Read {msg_id: u32, key: String},
+    ReadOk {in_reply_to: u32, value: serde_json::Value},
+    Write {msg_id: u32, key: String, value: serde_json::Value},
+    WriteOk {in_reply_to: u32},
+    Cas {msg_id: u32, key: String, from: serde_json::Value, to: serde_json::Value, create_if_not_exists: bool},
+    CasOk {in_reply_to: u32},
+    Error {in_reply_to: u32, code: u32, text: String}
+}
+
+impl KvBody {
+    pub fn in_reply_to(&self) -> Option<u32> {
+        match self {
+            KvBody::ReadOk {in_reply_to, ..} => Some(*in_reply_to),
+            KvBody::WriteOk {in_reply_to} => Some(*in_reply_to),
+            KvBody::CasOk {in_reply_to} => Some(*in_reply_to),
+            KvBody::Error {in_reply_to, ..} => Some(*in_reply_to),
+            _ => None
+        }
+    }
+}
+
+// arbitrary json value carried by broadcast and kafka messages
+// serde_json keeps object keys sorted, so serializing gives a canonical form for hashing
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(transparent)]
+pub struct Payload(pub serde_json::Value);
+
+impl Eq for Payload {}
+
+impl Hash for Payload {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.to_string().hash(state)
+    }
+}
+
+impl PartialOrd for Payload {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// null < bool < number < string < array < object, values of the same kind compare naturally
+impl Ord for Payload {
+    fn cmp(&self, other: &Self) -> Ordering {
+        fn rank(value: &serde_json::Value) -> u8 {
+            match value {
+                serde_json::Value::Null => 0,
+                serde_json::Value::Bool(_) => 1,
+                serde_json::Value::Number(_) => 2,
+                serde_json::Value::String(_) => 3,
+                serde_json::Value::Array(_) => 4,
+                serde_json::Value::Object(_) => 5,
+            }
+        }
+
+        fn cmp_values(a: &serde_json::Value, b: &serde_json::Value) -> Ordering {
+            use serde_json::Value;
+            match (a, b) {
+                (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+                (Value::Number(a), Value::Number(b)) => {
+                    match (a.as_i64(), b.as_i64()) {
+                        (Some(a), Some(b)) => a.cmp(&b),
+                        _ => a.as_f64().unwrap_or(f64::NAN).total_cmp(&b.as_f64().unwrap_or(f64::NAN))
+                            .then_with(|| a.to_string().cmp(&b.to_string()))
+                    }
+                },
+                (Value::String(a), Value::String(b)) => a.cmp(b),
+                (Value::Array(a), Value::Array(b)) => {
+                    a.iter().zip(b.iter())
+                        .map(|(a, b)| cmp_values(a, b))
+                        .find(|ord| ord.is_ne())
+                        .unwrap_or_else(|| a.len().cmp(&b.len()))
+                },
+                (Value::Object(a), Value::Object(b)) => {
+                    a.iter().zip(b.iter())
+                        .map(|((ka, va), (kb, vb))| ka.cmp(kb).then_with(|| cmp_values(va, vb)))
+                        .find(|ord| ord.is_ne())
+                        .unwrap_or_else(|| a.len().cmp(&b.len()))
+                },
+                _ => rank(a).cmp(&rank(b))
+            }
+        }
+
+        cmp_values(&self.0, &other.0)
+    }
+}
+
+impl std::fmt::Display for Payload {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl From<u32> for Payload {
+    fn from(value: u32) -> Self {
+        Payload(serde_json::Value::from(value))
+    }
 }
 
 pub enum MessageForm {
-    NodeMessage(Message<MessageBody>)
+    NodeMessage(Message<MessageBody>),
+    KvMessage(Message<KvBody>)
 }
 
 
@@ -148,6 +532,15 @@ pub struct MaelstromMessage(pub String);
 impl MaelstromMessage {
     pub fn to_deserialized_msg(&self) -> serde_json::Result<MessageForm> {
         let raw_msg: RawMessage = serde_json::from_str(&self.0)?;
+        if KV_SERVICES.contains(&raw_msg.src.as_str()) {
+            let body: KvBody = serde_json::from_value(raw_msg.body)?;
+            return Ok(MessageForm::KvMessage(Message {
+                src: raw_msg.src,
+                dest: raw_msg.dest,
+                body
+            }))
+        }
+
         let body: MessageBody = serde_json::from_value(raw_msg.body)?;
         Ok(MessageForm::NodeMessage(Message {
             src: raw_msg.src,
@@ -159,6 +552,7 @@ impl MaelstromMessage {
     pub fn from_deserialized_msg(des_msg: MessageForm) -> serde_json::Result<String> {
         let ser_message = match des_msg {
             MessageForm::NodeMessage(node_msg) => serde_json::to_string(&node_msg)?,
+            MessageForm::KvMessage(kv_msg) => serde_json::to_string(&kv_msg)?,
         };
 
         Ok(ser_message)
@@ -0,0 +1,290 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+
+// logical time, every commit gets the next one
+pub type Timestamp = u64;
+
+// start timestamp -> transactions running from it, shared with the transactions so a dropped one still leaves
+type ActiveTxns = Arc<Mutex<BTreeMap<Timestamp, usize>>>;
+
+// what a commit is checked against
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum MvccIsolation {
+    // first committer wins: a commit fails if a key it writes got a newer version after it started
+    #[default]
+    Snapshot,
+    // also fails if a key it read got a newer version, so no write skew gets through
+    Serializable
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MvccError<K> {
+    WriteConflict {key: K},
+    ReadConflict {key: K}
+}
+
+impl<K: Debug> Display for MvccError<K> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MvccError::WriteConflict {key} => write!(f, "key {key:?} was written by a newer transaction"),
+            MvccError::ReadConflict {key} => write!(f, "key {key:?} changed after it was read")
+        }
+    }
+}
+
+impl<K: Debug> Error for MvccError<K> {}
+
+// a transaction in progress, it holds no locks, only what it read and the writes it buffers until commit
+// it keeps its snapshot from `gc` until it commits, aborts or is dropped
+pub struct MvccTxn<K, V> {
+    start_ts: Timestamp,
+    isolation: MvccIsolation,
+    reads: HashSet<K>,
+    writes: HashMap<K, V>,
+    active: ActiveTxns
+}
+
+impl<K, V> Drop for MvccTxn<K, V> {
+    fn drop(&mut self) {
+        let mut active = self.active.lock().expect("error occur while releasing mvcc snapshot");
+        if let Some(running) = active.get_mut(&self.start_ts) {
+            *running -= 1;
+            if *running == 0 {
+                active.remove(&self.start_ts);
+            }
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> MvccTxn<K, V> {
+
+    pub fn start_ts(&self) -> Timestamp {
+        self.start_ts
+    }
+
+    // seen by this transaction's later reads, by others only once it commits
+    pub fn write(&mut self, key: K, value: V) {
+        self.writes.insert(key, value);
+    }
+}
+
+// multi-version key-value store
+// a transaction reads the newest versions committed before it started and validates at commit
+// old versions go away in `gc` once no running transaction can read them
+pub struct MvccStore<K, V> {
+    // key -> versions ordered by commit timestamp
+    versions: HashMap<K, Vec<(Timestamp, V)>>,
+    clock: Timestamp,
+    active: ActiveTxns
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> Default for MvccStore<K, V> {
+    fn default() -> Self {
+        MvccStore::new()
+    }
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> MvccStore<K, V> {
+
+    pub fn new() -> Self {
+        MvccStore {
+            versions: HashMap::new(),
+            clock: 0,
+            active: Arc::new(Mutex::new(BTreeMap::new()))
+        }
+    }
+
+    pub fn begin(&mut self, isolation: MvccIsolation) -> MvccTxn<K, V> {
+        *self.active.lock().expect("error occur while taking mvcc snapshot").entry(self.clock).or_insert(0) += 1;
+        MvccTxn {
+            start_ts: self.clock,
+            isolation,
+            reads: HashSet::new(),
+            writes: HashMap::new(),
+            active: self.active.clone()
+        }
+    }
+
+    // newest version committed at or before `ts`
+    pub fn read_at(&self, key: &K, ts: Timestamp) -> Option<&V> {
+        let versions = self.versions.get(key)?;
+        let visible = versions.partition_point(|(version_ts, _)| *version_ts <= ts);
+        versions[..visible].last().map(|(_, value)| value)
+    }
+
+    pub fn read(&self, txn: &mut MvccTxn<K, V>, key: &K) -> Option<V> {
+        if let Some(value) = txn.writes.get(key) {
+            return Some(value.clone())
+        }
+
+        txn.reads.insert(key.clone());
+        self.read_at(key, txn.start_ts).cloned()
+    }
+
+    fn changed_since(&self, key: &K, ts: Timestamp) -> bool {
+        self.versions.get(key).and_then(|versions| versions.last()).is_some_and(|(version_ts, _)| *version_ts > ts)
+    }
+
+    // installs the writes as versions of one new timestamp, or nothing if validation fails
+    pub fn commit(&mut self, mut txn: MvccTxn<K, V>) -> Result<Timestamp, MvccError<K>> {
+        if let Some(key) = txn.writes.keys().find(|key| self.changed_since(key, txn.start_ts)) {
+            return Err(MvccError::WriteConflict {key: key.clone()})
+        }
+        if txn.isolation == MvccIsolation::Serializable
+            && let Some(key) = txn.reads.iter().find(|key| self.changed_since(key, txn.start_ts)) {
+            return Err(MvccError::ReadConflict {key: key.clone()})
+        }
+
+        //read-only transactions leave no trace
+        if txn.writes.is_empty() {
+            return Ok(txn.start_ts)
+        }
+
+        self.clock += 1;
+        for (key, value) in std::mem::take(&mut txn.writes) {
+            self.versions.entry(key).or_default().push((self.clock, value));
+        }
+        Ok(self.clock)
+    }
+
+    // same as dropping the transaction
+    pub fn abort(&mut self, txn: MvccTxn<K, V>) {
+        drop(txn);
+    }
+
+    // drops every version no running or future transaction can read
+    // a key keeps its newest version visible at the oldest running snapshot and everything after it
+    pub fn gc(&mut self) -> usize {
+        let watermark = self.active.lock().expect("error occur while reading mvcc snapshots").keys().next().copied().unwrap_or(self.clock);
+
+        let mut dropped = 0;
+        for versions in self.versions.values_mut() {
+            let visible = versions.partition_point(|(version_ts, _)| *version_ts <= watermark);
+            let obsolete = visible.saturating_sub(1);
+            versions.drain(..obsolete);
+            dropped += obsolete;
+        }
+        dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store_with(values: &[(&'static str, u32)]) -> MvccStore<&'static str, u32> {
+        let mut store = MvccStore::new();
+        let mut txn = store.begin(MvccIsolation::Snapshot);
+        for (key, value) in values {
+            txn.write(*key, *value);
+        }
+        store.commit(txn).unwrap();
+        store
+    }
+
+    #[test]
+    fn reads_see_the_snapshot_they_started_from() {
+        let mut store = store_with(&[("x", 1)]);
+        let mut reader = store.begin(MvccIsolation::Snapshot);
+
+        let mut writer = store.begin(MvccIsolation::Snapshot);
+        writer.write("x", 2);
+        writer.write("y", 2);
+        store.commit(writer).unwrap();
+
+        assert_eq!(store.read(&mut reader, &"x"), Some(1));
+        assert_eq!(store.read(&mut reader, &"y"), None);
+        reader.write("x", 3);
+        assert_eq!(store.read(&mut reader, &"x"), Some(3));
+
+        let mut later = store.begin(MvccIsolation::Snapshot);
+        assert_eq!(store.read(&mut later, &"x"), Some(2));
+    }
+
+    #[test]
+    fn first_committer_wins_on_the_same_key() {
+        let mut store = store_with(&[("x", 1)]);
+        let mut first = store.begin(MvccIsolation::Snapshot);
+        let mut second = store.begin(MvccIsolation::Snapshot);
+        first.write("x", 2);
+        second.write("x", 3);
+
+        assert!(store.commit(first).is_ok());
+        assert_eq!(store.commit(second), Err(MvccError::WriteConflict {key: "x"}));
+        assert_eq!(store.read_at(&"x", store.clock), Some(&2));
+    }
+
+    type Commit = Result<Timestamp, MvccError<&'static str>>;
+
+    // both read x and y and each writes the other key, a write skew
+    fn write_skew(isolation: MvccIsolation) -> (Commit, Commit) {
+        let mut store = store_with(&[("x", 1), ("y", 1)]);
+        let mut first = store.begin(isolation);
+        let mut second = store.begin(isolation);
+        for txn in [&mut first, &mut second] {
+            store.read(txn, &"x");
+            store.read(txn, &"y");
+        }
+        first.write("x", 0);
+        second.write("y", 0);
+        (store.commit(first), store.commit(second))
+    }
+
+    #[test]
+    fn snapshot_isolation_lets_write_skew_through() {
+        let (first, second) = write_skew(MvccIsolation::Snapshot);
+        assert!(first.is_ok());
+        assert!(second.is_ok());
+    }
+
+    #[test]
+    fn serializable_refuses_a_commit_whose_reads_changed() {
+        let (first, second) = write_skew(MvccIsolation::Serializable);
+        assert!(first.is_ok());
+        assert_eq!(second, Err(MvccError::ReadConflict {key: "x"}));
+    }
+
+    #[test]
+    fn read_only_transactions_commit_at_their_snapshot() {
+        let mut store = store_with(&[("x", 1)]);
+        let mut reader = store.begin(MvccIsolation::Serializable);
+        store.read(&mut reader, &"x");
+        assert_eq!(store.commit(reader), Ok(1));
+    }
+
+    #[test]
+    fn gc_keeps_what_running_transactions_can_read() {
+        let mut store = store_with(&[("x", 1)]);
+        let mut reader = store.begin(MvccIsolation::Snapshot);
+        for value in 2..5 {
+            let mut writer = store.begin(MvccIsolation::Snapshot);
+            writer.write("x", value);
+            store.commit(writer).unwrap();
+        }
+
+        //the reader's version and everything after it stays
+        assert_eq!(store.gc(), 0);
+        assert_eq!(store.read(&mut reader, &"x"), Some(1));
+
+        store.abort(reader);
+        assert_eq!(store.gc(), 3);
+        assert_eq!(store.read_at(&"x", store.clock), Some(&4));
+        assert_eq!(store.read_at(&"x", 1), None);
+    }
+
+    #[test]
+    fn dropped_transaction_no_longer_holds_back_gc() {
+        let mut store = store_with(&[("x", 1)]);
+        let reader = store.begin(MvccIsolation::Snapshot);
+        let mut writer = store.begin(MvccIsolation::Snapshot);
+        writer.write("x", 2);
+        store.commit(writer).unwrap();
+
+        assert_eq!(store.gc(), 0);
+        drop(reader);
+        assert_eq!(store.gc(), 1);
+    }
+}
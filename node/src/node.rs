@@ -1,22 +1,93 @@
 use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::future::Future;
 use std::collections::{HashSet, HashMap};
-use std::sync::Arc;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::{Component, Path, PathBuf};
+use std::io;
+use std::sync::{Arc, OnceLock};
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
-use tokio::sync::{Mutex, oneshot, mpsc};
+use tokio::sync::{Mutex, mpsc};
 use crate::counter::Counter;
-use crate::message::{MaelstromMessage, Message, MessageBody, MessageForm};
-use crate::id_generator::IdGenerator;
-use crate::kafka::Kafka;
+use crate::message::{MaelstromMessage, Message, MessageBody, MessageForm, Payload};
+use crate::id_generator::{IdError, IdFormat, IdGenerator, IdLease};
+use crate::kv::{KvClient, KvError, LIN_KV, LWW_KV};
+use crate::kv_kafka::{KafkaBackend, KvKafka};
+use crate::rpc::{Rpc, RpcError, RPC_TIMEOUT};
+use crate::kafka::{Kafka, KafkaError, PollLimits, ProducerSeq, RetentionPolicy, BATCH_LEASE, DEFAULT_GROUP};
+use crate::snapshot::SnapshotError;
+use crate::log_storage::FsyncPolicy;
+use crate::plumtree::{BroadcastMode, Plumtree};
+use crate::txn::{OpKind, ReplicatedTxn, TxnIsolation, TxnStore, TxnWorkload};
+use crate::list_append::ListAppend;
+use crate::topic::{is_partition_key, partition_key, PartitionKeys, TopicConfig, Topics};
+use crate::raft::{Raft, DEFAULT_SNAPSHOT_EVERY};
+use crate::raft_storage::RaftStorage;
+use crate::lin_kv::{KvCommand, KvStore};
+
+// a request handler, for requests the node makes on its own behalf
+type Handler = fn(&mut Node, Message<MessageBody>) -> Result<(), Box<dyn Error>>;
 
 pub struct Node {
     id: Option<String>,
     node_ids: Option<Vec<String>>,
     id_generator: Option<IdGenerator>,
-    saved_messages: HashSet<u32>,
-    broadcast_pending: Arc<Mutex<HashMap<u32, oneshot::Sender<()>>>>,
+    id_format: IdFormat,
+    // directory keeping the id high-water mark of every node across restarts
+    id_state_dir: Option<PathBuf>,
+    // block size of ids leased from lin-kv, local generation is used when unset
+    id_lease_block: Option<u64>,
+    id_lease: Option<IdLease>,
+    // clients of maelstrom services by service name
+    kv_clients: HashMap<String, KvClient>,
+    // requests to other nodes, also the source of msg_id for everything this node sends on its own
+    rpc: Rpc,
+    saved_messages: HashSet<Payload>,
+    // values each peer is known to hold
+    peer_knowledge: Arc<Mutex<PeerKnowledge>>,
     output_sender: mpsc::UnboundedSender<MessageForm>,
+    // messages the node addresses to itself, the output writer hands them back here
+    inbox: mpsc::UnboundedReceiver<MessageForm>,
+    // own node id as seen by the output writer, set on init
+    address: Arc<OnceLock<String>>,
     counter: Counter,
-    kafka: Kafka
+    kafka: Kafka,
+    // batches coordinated here: txn_id -> whether it was committed
+    batch_outcomes: Arc<Mutex<HashMap<String, bool>>>,
+    kafka_backend: KafkaBackend,
+    // directory under which every node keeps its kafka logs on disk
+    kafka_dir: Option<(PathBuf, FsyncPolicy)>,
+    // snapshot a node starting without logs restores the keys it owns from
+    kafka_snapshot: Option<PathBuf>,
+    // the only place `export_snapshot` writes to, clients name a file inside it
+    kafka_snapshot_dir: Option<PathBuf>,
+    kafka_retention: RetentionPolicy,
+    // limits for polls that don't set their own
+    poll_limits: PollLimits,
+    // log_key -> retention overriding `kafka_retention` for it
+    kafka_key_retention: HashMap<String, RetentionPolicy>,
+    // partitioned topics on top of kafka keys
+    topics: Topics,
+    // set when logs are kept in lin-kv instead of `kafka`
+    kv_kafka: Option<KvKafka>,
+    broadcast_mode: BroadcastMode,
+    plumtree: Plumtree,
+    // registers of the txn-rw-register workload
+    txn_store: Option<TxnStore>,
+    // peer -> queue of transactions it has yet to get, started with the first one
+    txn_replication: HashMap<String, mpsc::UnboundedSender<ReplicatedTxn>>,
+    txn_isolation: TxnIsolation,
+    txn_workload: TxnWorkload,
+    // set when `txn` requests are list appends
+    list_append: Option<ListAppend>,
+    // lin-kv requests are served through raft when enabled
+    raft_enabled: bool,
+    // directory under which every node keeps its raft log, kept in memory when unset
+    raft_dir: Option<PathBuf>,
+    // applied entries between two raft snapshots
+    raft_snapshot_every: u64,
+    raft: Option<Raft<KvStore>>
 }
 
 impl Node {
@@ -24,11 +95,18 @@ impl Node {
         self.id_generator.as_mut().unwrap()
     }
 
+    fn next_msg_id(&mut self) -> u32 {
+        self.rpc.next_msg_id()
+    }
+
     pub async fn new() -> Self {
         let (tx, rx) = mpsc::unbounded_channel::<MessageForm>();
+        let (inbox_tx, inbox_rx) = mpsc::unbounded_channel::<MessageForm>();
+        let address = Arc::new(OnceLock::new());
         //init output writer
-        tokio::spawn(async {
-            Node::stdout_writer(rx).await
+        let writer_address = address.clone();
+        tokio::spawn(async move {
+            Node::stdout_writer(rx, inbox_tx, writer_address).await
         });
 
 
@@ -36,30 +114,192 @@ impl Node {
             id: None,
             node_ids: None,
             id_generator: None,
+            id_format: IdFormat::default(),
+            id_state_dir: None,
+            id_lease_block: None,
+            id_lease: None,
+            kv_clients: HashMap::new(),
+            rpc: Rpc::new(tx.clone()),
             saved_messages: HashSet::new(),
-            broadcast_pending: Arc::new(Mutex::new(HashMap::new())),
+            peer_knowledge: Arc::new(Mutex::new(PeerKnowledge::default())),
             output_sender: tx,
+            inbox: inbox_rx,
+            address,
             counter: Counter::new(),
             kafka: Kafka::new(),
+            batch_outcomes: Arc::new(Mutex::new(HashMap::new())),
+            kafka_backend: KafkaBackend::default(),
+            kafka_dir: None,
+            kafka_snapshot: None,
+            kafka_snapshot_dir: None,
+            kafka_retention: RetentionPolicy::default(),
+            poll_limits: PollLimits::default(),
+            kafka_key_retention: HashMap::new(),
+            topics: Topics::new(),
+            kv_kafka: None,
+            broadcast_mode: BroadcastMode::default(),
+            plumtree: Plumtree::new(),
+            txn_store: None,
+            txn_replication: HashMap::new(),
+            txn_isolation: TxnIsolation::default(),
+            txn_workload: TxnWorkload::default(),
+            list_append: None,
+            raft_enabled: false,
+            raft_dir: None,
+            raft_snapshot_every: DEFAULT_SNAPSHOT_EVERY,
+            raft: None,
         }
     }
 
-    async fn init_node(&mut self, node_id: String, node_ids: Vec<String>) {
-        let id_generator = IdGenerator::new(node_id.clone());
+    pub fn with_id_format(mut self, id_format: IdFormat) -> Self {
+        self.id_format = id_format;
+        self
+    }
+
+    pub fn with_id_state_dir(mut self, id_state_dir: PathBuf) -> Self {
+        self.id_state_dir = Some(id_state_dir);
+        self
+    }
+
+    pub fn with_id_lease(mut self, block_size: u64) -> Self {
+        self.id_lease_block = Some(block_size);
+        self
+    }
+
+    pub fn with_kafka_backend(mut self, kafka_backend: KafkaBackend) -> Self {
+        self.kafka_backend = kafka_backend;
+        self
+    }
+
+    pub fn with_kafka_dir(mut self, kafka_dir: PathBuf, fsync: FsyncPolicy) -> Self {
+        self.kafka_dir = Some((kafka_dir, fsync));
+        self
+    }
+
+    pub fn with_kafka_snapshot(mut self, snapshot: PathBuf) -> Self {
+        self.kafka_snapshot = Some(snapshot);
+        self
+    }
+
+    pub fn with_kafka_snapshot_dir(mut self, snapshot_dir: PathBuf) -> Self {
+        self.kafka_snapshot_dir = Some(snapshot_dir);
+        self
+    }
+
+    pub fn with_topic(mut self, topic: String, config: TopicConfig) -> Self {
+        assert!(config.partitions > 0, "topic {topic} needs at least one partition");
+        self.topics.configure(topic, config);
+        self
+    }
+
+    pub fn with_kafka_retention(mut self, retention: RetentionPolicy) -> Self {
+        self.kafka_retention = retention;
+        self
+    }
+
+    pub fn with_kafka_key_retention(mut self, key: String, retention: RetentionPolicy) -> Self {
+        self.kafka_key_retention.insert(key, retention);
+        self
+    }
+
+    pub fn with_poll_limits(mut self, poll_limits: PollLimits) -> Self {
+        self.poll_limits = poll_limits;
+        self
+    }
+
+    pub fn with_txn_isolation(mut self, txn_isolation: TxnIsolation) -> Self {
+        self.txn_isolation = txn_isolation;
+        self
+    }
+
+    pub fn with_txn_workload(mut self, txn_workload: TxnWorkload) -> Self {
+        self.txn_workload = txn_workload;
+        self
+    }
+
+    pub fn with_raft(mut self) -> Self {
+        self.raft_enabled = true;
+        self
+    }
+
+    pub fn with_raft_dir(mut self, raft_dir: PathBuf) -> Self {
+        self.raft_dir = Some(raft_dir);
+        self
+    }
+
+    pub fn with_raft_snapshot_every(mut self, entries: u64) -> Self {
+        assert!(entries > 0, "raft snapshots need at least one entry between them");
+        self.raft_snapshot_every = entries;
+        self
+    }
+
+    pub fn with_broadcast_mode(mut self, broadcast_mode: BroadcastMode) -> Self {
+        self.broadcast_mode = broadcast_mode;
+        self
+    }
+
+    // a node missing from the cluster would share another node's index and with it its ids
+    async fn init_node(&mut self, node_id: String, node_ids: Vec<String>) -> Result<(), InitError> {
+        let node_index = node_ids.iter().position(|node| node.eq(&node_id)).ok_or_else(|| InitError::NotInCluster {node_id: node_id.clone()})?;
+        let state_path = self.id_state_dir.as_ref().map(|dir| dir.join(format!("{node_id}.ids")));
+        let id_generator = IdGenerator::new(self.id_format, node_index, node_ids.len(), state_path.as_deref())?;
         self.id_generator = Some(id_generator);
-        self.id = Some(node_id.clone());
+        let lin_kv = KvClient::new(LIN_KV, node_id.clone(), self.output_sender.clone());
+        self.id_lease = self.id_lease_block.map(|block_size| IdLease::new(lin_kv.clone(), block_size));
+        if let Some((kafka_dir, fsync)) = &self.kafka_dir {
+            self.kafka = Kafka::open(&kafka_dir.join(&node_id), *fsync).map_err(InitError::KafkaLog)?;
+        }
+        //a restarted node keeps what it recovered from disk
+        if let Some(snapshot) = &self.kafka_snapshot && self.kafka_backend == KafkaBackend::Owner && self.kafka.is_empty() {
+            let owns = |key: &str| key_owner_in(&node_ids, &self.topics, key).eq(&node_id);
+            self.kafka.import_snapshot(snapshot, owns).map_err(InitError::Snapshot)?;
+        }
+        self.kafka.set_retention(self.kafka_retention.clone()).map_err(InitError::Retention)?;
+        for (key, retention) in &self.kafka_key_retention {
+            self.kafka.set_key_retention(key.clone(), retention.clone()).map_err(InitError::Retention)?;
+        }
+        if self.kafka_backend == KafkaBackend::LinKv {
+            self.kv_kafka = Some(KvKafka::new(lin_kv.clone()));
+        }
+        if self.txn_workload == TxnWorkload::ListAppend {
+            let lww_kv = KvClient::new(LWW_KV, node_id.clone(), self.output_sender.clone());
+            self.list_append = Some(ListAppend::new(node_id.clone(), lin_kv.clone(), lww_kv.clone()));
+            self.kv_clients.insert(String::from(LWW_KV), lww_kv);
+        }
+        self.kv_clients.insert(String::from(LIN_KV), lin_kv);
+        self.txn_store = Some(TxnStore::new(node_id.clone(), self.txn_isolation));
+        let _ = self.address.set(node_id.clone());
+        self.rpc.set_node_id(node_id.clone());
         self.node_ids = Some(node_ids.clone());
-        self.counter.init_counter_replication(node_id, node_ids, self.output_sender.clone())
+        self.peer_knowledge.lock().await.peers = node_ids.len() - 1;
+        if self.raft_enabled {
+            let storage = match &self.raft_dir {
+                Some(raft_dir) => RaftStorage::open(&raft_dir.join(&node_id)).map_err(InitError::RaftLog)?,
+                None => RaftStorage::new()
+            };
+            let raft = Raft::new(node_id.clone(), node_ids.clone(), storage, KvStore::default(), self.raft_snapshot_every, self.rpc.clone()).map_err(InitError::RaftLog)?;
+            raft.start();
+            self.raft = Some(raft);
+        }
+        if self.broadcast_mode == BroadcastMode::Plumtree {
+            self.plumtree.init_plumtree(node_id.clone(), node_ids.clone(), self.output_sender.clone()).await;
+        }
+        //set last, a node whose init failed part way stays uninitialized
+        self.id = Some(node_id.clone());
+        self.counter.init_counter_replication(node_id, node_ids, self.output_sender.clone());
+        Ok(())
     }
 
     async fn handle_init(&mut self, msg: Message<MessageBody>) -> Result<(), Box<dyn Error>> {
-        self.init_node(msg.node_id().unwrap(), msg.node_ids().unwrap()).await;
+        let in_reply_to = *msg.msg_id().unwrap();
+        let body = match self.init_node(msg.node_id().unwrap(), msg.node_ids().unwrap()).await {
+            Ok(()) => MessageBody::InitOk {in_reply_to},
+            Err(e) => MessageBody::Error {in_reply_to, code: e.code(), text: e.to_string()}
+        };
         let msg = Message {
             src: String::from(&msg.dest),
             dest: String::from(&msg.src),
-            body: MessageBody::InitOk {
-                in_reply_to: *msg.msg_id().unwrap()
-            }
+            body
         };
         self.output_sender.send(msg.into()).map_err(|e| Box::new(e) as Box<dyn Error>)
     }
@@ -81,21 +321,63 @@ impl Node {
 
     async fn handle_generate(&mut self, msg: Message<MessageBody>) -> Result<(), Box<dyn Error>> {
         let msg_id = msg.msg_id().unwrap();
-        let id = &self.id_generator().generate();
-        let msg = Message {
-            src: String::from(&msg.dest),
-            dest: String::from(&msg.src),
-            body: MessageBody::GenerateOk {
+
+        if let Some(id_lease) = self.id_lease.clone() {
+            //leasing may wait on lin-kv, whose replies come through this same loop
+            let output_sender = self.output_sender.clone();
+            let msg_id = *msg_id;
+            tokio::spawn(async move {
+                let body = match id_lease.next_id().await {
+                    Ok(id) => MessageBody::GenerateOk {
+                        msg_id,
+                        in_reply_to: msg_id,
+                        id: serde_json::Value::from(id)
+                    },
+                    Err(e) => MessageBody::Error {
+                        in_reply_to: msg_id,
+                        code: 11,
+                        text: format!("no ids leased: {e}")
+                    }
+                };
+                let msg = Message {
+                    src: String::from(&msg.dest),
+                    dest: String::from(&msg.src),
+                    body
+                };
+                let _ = output_sender.send(msg.into());
+            });
+            return Ok(())
+        }
+
+        let body = match self.id_generator().generate() {
+            Ok(id) => MessageBody::GenerateOk {
                 msg_id: *msg_id,
                 in_reply_to: *msg_id,
-                id: *id
+                id
+            },
+            //nothing was issued, so the client may just retry
+            Err(e) => MessageBody::Error {
+                in_reply_to: *msg_id,
+                code: 11,
+                text: format!("error occur while persisting id high-water mark: {e}")
             }
         };
+        let msg = Message {
+            src: String::from(&msg.dest),
+            dest: String::from(&msg.src),
+            body
+        };
         self.output_sender.send(msg.into()).map_err(|e| Box::new(e) as Box<dyn Error>)
     }
 
-    fn handle_topology(&self, msg: Message<MessageBody>) -> Result<(), Box<dyn Error>> {
+    async fn handle_topology(&mut self, msg: Message<MessageBody>) -> Result<(), Box<dyn Error>> {
         let msg_id = msg.msg_id().unwrap();
+        if self.broadcast_mode == BroadcastMode::Plumtree {
+            let MessageBody::Topology { topology, .. } = &msg.body else { unreachable!() };
+            if let Some(neighbours) = topology.get(&msg.dest) {
+                self.plumtree.set_neighbours(neighbours.clone()).await;
+            }
+        }
         let msg = Message {
             src: String::from(&msg.dest),
             dest: String::from(&msg.src),
@@ -112,8 +394,24 @@ impl Node {
             _ => unreachable!()
         };
 
-        if self.saved_messages.insert(message) {
-            self.replicate_to_peers(message).await;
+        let from_peer = self.is_peer(&msg.src);
+        if from_peer {
+            self.peer_knowledge.lock().await.learn(&msg.src, [message.clone()]);
+        }
+
+        if self.saved_messages.insert(message.clone()) {
+            match self.broadcast_mode {
+                BroadcastMode::Flood => self.replicate_to_peers(&message).await,
+                BroadcastMode::Plumtree => {
+                    let from = from_peer.then_some(&msg.src);
+                    let eager_peers = self.plumtree.on_new_message(from, &message).await;
+                    for peer in &eager_peers {
+                        self.send_broadcast(peer, &message).await;
+                    }
+                }
+            }
+        } else if from_peer && self.broadcast_mode == BroadcastMode::Plumtree {
+            self.plumtree.on_duplicate_message(&msg.src, &message).await;
         }
 
         let msg = Message {
@@ -127,81 +425,112 @@ impl Node {
         self.output_sender.send(msg.into()).map_err(|e| Box::new(e) as Box<dyn Error>)
     }
 
-    async fn replicate_to_peers(&mut self, message: u32) {
+    fn is_peer(&self, node: &String) -> bool {
+        self.node_ids.as_ref().is_some_and(|nodes| nodes.contains(node))
+    }
+
+    async fn replicate_to_peers(&mut self, message: &Payload) {
         let nodes = self.node_ids.as_ref().unwrap().clone();
         let cur_node = self.id.as_ref().unwrap().to_string();
 
         for node in &nodes {
             //sending only to other nodes
             if cur_node.ne(node) {
-                let generated_id = self.id_generator().generate();
-                let pending_res = self.broadcast_pending.clone();
-                let src = cur_node.clone();
-                let dest = String::from(node);
-                let output_sender = self.output_sender.clone();
+                self.send_broadcast(node, message).await;
+            }
+        }
 
-                let msg = Message {
-                    src,
-                    dest,
-                    body: MessageBody::Broadcast {
-                        msg_id: generated_id,
-                        message
-                    }
-                };
+    }
 
-                let _  = output_sender.send(msg.clone().into());
+    // pushes the value to a single peer, retrying until it acks
+    async fn send_broadcast(&mut self, node: &String, message: &Payload) {
+        let cur_node = self.id.as_ref().unwrap().to_string();
 
-                tokio::spawn(async move {
+        //skipping peers that already hold the value
+        if self.peer_knowledge.lock().await.knows(node, message) {
+            return
+        }
 
-                    let msg = msg.clone();
+        let generated_id = self.next_msg_id();
+        let rpc = self.rpc.clone();
+        let peer_knowledge = self.peer_knowledge.clone();
+        let src = cur_node.clone();
+        let dest = String::from(node);
+        let output_sender = self.output_sender.clone();
 
-                    let (tx, mut rx) = oneshot::channel::<()>();
+        let message = message.clone();
+        let msg = Message {
+            src,
+            dest,
+            body: MessageBody::Broadcast {
+                msg_id: generated_id,
+                message: message.clone()
+            }
+        };
 
-                    let mut guard = pending_res.lock().await;
+        tokio::spawn(async move {
 
-                    if let None = guard.get_mut(&generated_id) {
-                        guard.insert(generated_id, tx);
-                    }
+            let msg = msg.clone();
 
-                    //dropping mutex guard before sending msg attempts
-                    drop(guard);
+            //every attempt carries the same msg_id, so whichever of them is answered first ends the retries
+            let mut rx = rpc.expect_reply(generated_id).await;
 
-                    let mut attempts = 5;
-                    loop {
+            let mut attempts = 5;
+            loop {
 
-                        let _ = output_sender.send(msg.clone().into());
+                let _ = output_sender.send(msg.clone().into());
 
-                        tokio::select! {
-                            _ = &mut rx => break,
-                            _ = tokio::time::sleep(tokio::time::Duration::from_millis(300)) => {
-                                attempts -= 1;
-                                if attempts == 0 {
-                                    eprintln!("no response for msg_id:{} and value:{}", generated_id, message);
-                                    break
-                                }
-                            }
+                tokio::select! {
+                    _ = &mut rx => {
+                        peer_knowledge.lock().await.learn(&msg.dest, [message]);
+                        break
+                    },
+                    _ = tokio::time::sleep(tokio::time::Duration::from_millis(300)) => {
+                        //peer may have got the value from someone else meanwhile
+                        if peer_knowledge.lock().await.knows(&msg.dest, &message) {
+                            rpc.forget(generated_id).await;
+                            break
+                        }
+                        attempts -= 1;
+                        if attempts == 0 {
+                            eprintln!("no response for msg_id:{} and value:{}", generated_id, message);
+                            rpc.forget(generated_id).await;
+                            break
                         }
                     }
-
-                });
+                }
             }
-        }
+
+        });
 
     }
 
-    async fn handle_broadcast_ok(&mut self, msg: Message<MessageBody>) -> Result<(), Box<dyn Error>> {
+    async fn handle_ihave(&mut self, msg: Message<MessageBody>) {
+        let MessageBody::IHave { messages } = msg.body else { return };
 
-        let MessageBody::BroadcastOk { in_reply_to } = msg.body else {
-            return Ok(());
-        };
+        //an announcing peer holds what it announces
+        self.peer_knowledge.lock().await.learn(&msg.src, messages.iter().cloned());
+
+        let unknown = messages.into_iter().filter(|message| !self.saved_messages.contains(message)).collect::<Vec<Payload>>();
+        if !unknown.is_empty() {
+            self.plumtree.on_ihave(&msg.src, unknown).await;
+        }
+    }
+
+    async fn handle_graft(&mut self, msg: Message<MessageBody>) {
+        let MessageBody::Graft { messages } = msg.body else { return };
 
-        let mut guard = self.broadcast_pending.lock().await;
+        self.plumtree.on_graft(&msg.src).await;
 
-        if let Some(sender) = guard.remove(&in_reply_to) {
-            let _ = sender.send(());
+        for message in messages {
+            if self.saved_messages.contains(&message) {
+                self.send_broadcast(&msg.src, &message).await;
+            }
         }
+    }
 
-        Ok(())
+    async fn handle_prune(&mut self, msg: Message<MessageBody>) {
+        self.plumtree.on_prune(&msg.src).await;
     }
 
     async fn handle_read(&self, msg: Message<MessageBody>) -> Result<(), Box<dyn Error>> {
@@ -214,11 +543,11 @@ impl Node {
             dest: String::from(&msg.src),
             body: MessageBody::ReadOk {
                 in_reply_to: msg_id,
-                value: v
+                value: serde_json::Value::from(v)
             }
         };
 
-        Ok(self.output_sender.send(msg.into()).map_err(|e| Box::new(e) as Box<dyn Error>)?)
+        self.output_sender.send(msg.into()).map_err(|e| Box::new(e) as Box<dyn Error>)
     }
 
     async fn handle_add(&mut self, msg: Message<MessageBody>) -> Result<(), Box<dyn Error>> {
@@ -237,7 +566,7 @@ impl Node {
             }
         };
 
-        Ok(self.output_sender.send(res.into()).map_err(|e| Box::new(e) as Box<dyn Error>)?)
+        self.output_sender.send(res.into()).map_err(|e| Box::new(e) as Box<dyn Error>)
     }
 
     async fn handle_share_counter_state(&mut self, msg: Message<MessageBody>) {
@@ -246,159 +575,1344 @@ impl Node {
         self.counter.add(&msg.src, value, true).await;
     }
 
-    fn handle_send(&mut self, node_msg: Message<MessageBody>) -> Result<(), Box<dyn Error>>{
-        let MessageBody::Send {msg_id, key, msg} = node_msg.body else {
-            return Ok(())
-        };
-
-        let offset = self.kafka.write_log(key, msg);
+    // every kafka key is owned by one node, chosen by hashing the key over the cluster
+    fn key_owner(&self, key: &str) -> String {
+        key_owner_in(self.node_ids.as_ref().unwrap(), &self.topics, key).clone()
+    }
 
-        let msg = Message {
-            src: String::from(&node_msg.dest),
-            dest: String::from(&node_msg.src),
-            body: MessageBody::SendOk {
-                in_reply_to: msg_id,
-                offset,
+    // splits per-key values into the ones this node owns and the ones grouped by their owner
+    fn split_by_owner<V>(&self, values: HashMap<String, V>) -> (HashMap<String, V>, HashMap<String, HashMap<String, V>>) {
+        let cur_node = self.id.as_ref().unwrap();
+        let mut local = HashMap::new();
+        let mut remote: HashMap<String, HashMap<String, V>> = HashMap::new();
+
+        for (key, value) in values {
+            let owner = self.key_owner(&key);
+            if owner.eq(cur_node) {
+                local.insert(key, value);
+            } else {
+                remote.entry(owner).or_default().insert(key, value);
             }
-        };
+        }
 
-        Ok(self.output_sender.send(msg.into()).map_err(|e| Box::new(e) as Box<dyn Error>)?)
+        (local, remote)
     }
 
-    fn handle_poll(&self, msg: Message<MessageBody>) -> Result<(), Box<dyn Error>> {
-        let MessageBody::Poll {msg_id, offsets} = msg.body else {
-            return Ok(())
-        };
+    // asks every owner in turn and answers the client once all of them replied
+    // `merge` folds an owner's reply into the local answer, the first failure is returned to the client instead
+    fn gather_from_owners<V: Send + 'static>(
+        &self,
+        client_msg: (String, String, u32),
+        remote: HashMap<String, V>,
+        mut reply: MessageBody,
+        make_body: impl Fn(u32, V) -> MessageBody + Send + Sync + 'static,
+        merge: impl Fn(&mut MessageBody, MessageBody) + Send + Sync + 'static
+    ) {
+        let (node, client, client_msg_id) = client_msg;
+        let rpc = self.rpc.clone();
+        let output_sender = self.output_sender.clone();
+
+        tokio::spawn(async move {
+            for (owner, values) in remote {
+                match rpc.call(&owner, |msg_id| make_body(msg_id, values)).await {
+                    Ok(owner_reply) => merge(&mut reply, owner_reply),
+                    Err(e) => {
+                        reply = MessageBody::Error {
+                            in_reply_to: client_msg_id,
+                            code: rpc_error_code(&e),
+                            text: format!("key owner {owner} failed: {e}")
+                        };
+                        break
+                    }
+                }
+            }
 
-        let msgs = self.kafka.read_logs(offsets);
+            let msg = Message {
+                src: node,
+                dest: client,
+                body: reply
+            };
+            let _ = output_sender.send(msg.into());
+        });
+    }
 
+    fn reply_error(&self, node: String, client: String, in_reply_to: u32, code: u32, text: String) -> Result<(), Box<dyn Error>> {
         let msg = Message {
-            src: String::from(&msg.dest),
-            dest: String::from(&msg.src),
-            body: MessageBody::PollOk {
-                in_reply_to: msg_id,
-                msgs,
-            }
+            src: node,
+            dest: client,
+            body: MessageBody::Error {in_reply_to, code, text}
         };
+        self.output_sender.send(msg.into()).map_err(|e| Box::new(e) as Box<dyn Error>)
+    }
 
-        Ok(self.output_sender.send(msg.into()).map_err(|e| Box::new(e) as Box<dyn Error>)?)
+    // for requests only the owner kafka backend can serve
+    fn reply_unsupported(&self, node: String, client: String, in_reply_to: u32, what: &str) -> Result<(), Box<dyn Error>> {
+        self.reply_error(node, client, in_reply_to, 10, format!("{what} need the owner kafka backend"))
     }
 
-    fn handle_commit_offsets(&mut self, node_msg: Message<MessageBody>) -> Result<(), Box<dyn Error>> {
-        let MessageBody::CommitOffsets {msg_id, offsets} = node_msg.body else {
-            return Ok(())
+    // answers a request with `handler`'s answer to `make_body`, turned into the client's reply by `convert`
+    // topic requests go through here as requests on partition keys, which reach their owners like any other key
+    // the handler runs right away and answers this node itself, once on its own or once the key owners did
+    async fn relay_to_self(
+        &mut self,
+        client_msg: (String, String, u32),
+        make_body: impl FnOnce(u32) -> MessageBody,
+        handler: Handler,
+        convert: impl FnOnce(MessageBody) -> MessageBody + Send + 'static
+    ) -> Result<(), Box<dyn Error>> {
+        let (node, client, in_reply_to) = client_msg;
+        let msg_id = self.next_msg_id();
+        let own_reply = self.rpc.expect_reply(msg_id).await;
+
+        let request = Message {
+            src: node.clone(),
+            dest: node.clone(),
+            body: make_body(msg_id)
         };
+        if let Err(e) = handler(self, request) {
+            self.rpc.forget(msg_id).await;
+            return Err(e)
+        }
 
-        let msg = Message {
-            src: String::from(&node_msg.dest),
-            dest: String::from(&node_msg.src),
-            body: MessageBody::CommitOffsetsOk {
-                in_reply_to: msg_id,
-            }
-        };
+        let output_sender = self.output_sender.clone();
+        tokio::spawn(async move {
+            let Ok(own_reply) = own_reply.await else { return };
+            let mut body = convert(own_reply);
+            body.set_in_reply_to(in_reply_to);
+            let msg = Message {
+                src: node,
+                dest: client,
+                body
+            };
+            let _ = output_sender.send(msg.into());
+        });
+        Ok(())
+    }
 
-        self.kafka.commit_offsets(node_msg.src, offsets);
+    // moves the values of every partition in `by_topic` to its partition key
+    fn partition_keys<V>(&self, by_topic: HashMap<String, HashMap<usize, V>>) -> Result<(HashMap<String, V>, PartitionKeys), String> {
+        let mut values = HashMap::new();
+        let mut partitions = HashMap::new();
+        for (topic, by_partition) in by_topic {
+            for (partition, value) in by_partition {
+                self.topics.check_partition(&topic, partition)?;
+                let key = partition_key(&topic, partition);
+                values.insert(key.clone(), value);
+                partitions.insert(key, (topic.clone(), partition));
+            }
+        }
+        Ok((values, partitions))
+    }
 
+    // every node but this one, for requests every key owner has to answer
+    fn other_nodes(&self) -> HashMap<String, ()> {
+        let cur_node = self.id.as_ref().unwrap();
+        self.node_ids.iter().flatten().filter(|node| node.ne(&cur_node)).map(|node| (node.clone(), ())).collect()
+    }
 
+    // answers the client once `reply` resolves, for handlers waiting on maelstrom services
+    fn reply_when_ready(&self, node: String, client: String, in_reply_to: u32, reply: impl Future<Output = Result<MessageBody, KvError>> + Send + 'static) {
+        let output_sender = self.output_sender.clone();
+        tokio::spawn(async move {
+            let body = match reply.await {
+                Ok(body) => body,
+                Err(e) => MessageBody::Error {
+                    in_reply_to,
+                    code: e.code(),
+                    text: e.to_string()
+                }
+            };
 
-        Ok(self.output_sender.send(msg.into()).map_err(|e| Box::new(e) as Box<dyn Error>)?)
+            let msg = Message {
+                src: node,
+                dest: client,
+                body
+            };
+            let _ = output_sender.send(msg.into());
+        });
     }
 
-    fn handle_list_commited_offsets(&self, msg: Message<MessageBody>) -> Result<(), Box<dyn Error>> {
-        let MessageBody::ListCommittedOffsets {msg_id, keys} = msg.body else {
+    fn handle_send(&mut self, node_msg: Message<MessageBody>) -> Result<(), Box<dyn Error>>{
+        let MessageBody::Send {msg_id, key, msg, producer_id, seq} = node_msg.body else {
             return Ok(())
         };
+        let producer = producer_id.zip(seq).map(|(producer_id, seq)| ProducerSeq {producer_id, seq});
+
+        if let Some(kv_kafka) = self.kv_kafka.clone() {
+            self.reply_when_ready(node_msg.dest, node_msg.src, msg_id, async move {
+                let offset = kv_kafka.write_log(key, msg, producer).await?;
+                Ok(MessageBody::SendOk {in_reply_to: msg_id, offset})
+            });
+            return Ok(())
+        }
+
+        let owner = self.key_owner(&key);
+        if owner.ne(&node_msg.dest) {
+            //only the owner assigns offsets, its answer is relayed as is
+            let client_msg = (node_msg.dest, node_msg.src, msg_id);
+            let reply = MessageBody::SendOk {in_reply_to: msg_id, offset: 0};
+            let remote = HashMap::from([(owner, (key, msg, producer))]);
+            self.gather_from_owners(
+                client_msg,
+                remote,
+                reply,
+                |msg_id, (key, msg, producer)| {
+                    let (producer_id, seq) = producer.map(|p| (p.producer_id, p.seq)).unzip();
+                    MessageBody::Send {msg_id, key, msg, producer_id, seq}
+                },
+                move |reply, owner_reply| {
+                    *reply = owner_reply;
+                    reply.set_in_reply_to(msg_id);
+                }
+            );
+            return Ok(())
+        }
 
-        let commited_offsets = self.kafka.get_commited_offsets(&msg.src, keys);
+        let body = match self.kafka.write_log(key, msg, producer.as_ref()) {
+            Ok(offset) => MessageBody::SendOk {in_reply_to: msg_id, offset},
+            Err(e) => kafka_error_reply(msg_id, e)
+        };
 
         let msg = Message {
-            src: String::from(&msg.dest),
-            dest: String::from(&msg.src),
-            body: MessageBody::ListCommittedOffsetsOk {
-                in_reply_to: msg_id,
-                offsets: commited_offsets
-            }
+            src: String::from(&node_msg.dest),
+            dest: String::from(&node_msg.src),
+            body
         };
 
-        Ok(self.output_sender.send(msg.into()).map_err(|e| Box::new(e) as Box<dyn Error>)?)
+        self.output_sender.send(msg.into()).map_err(|e| Box::new(e) as Box<dyn Error>)
     }
 
-    async fn handle_message(&mut self, msg_form: MessageForm) -> Result<(), Box<dyn Error>> {
+    fn handle_send_batch(&mut self, node_msg: Message<MessageBody>) -> Result<(), Box<dyn Error>> {
+        let MessageBody::SendBatch {msg_id, msgs} = node_msg.body else {
+            return Ok(())
+        };
 
-        match msg_form {
-            MessageForm::NodeMessage(node_msg) => {
-                let msg_type = node_msg.typ();
-                match msg_type.as_str() {
-                    "init" => self.handle_init(node_msg).await?,
-                    "echo" => self.handle_echo(node_msg)?,
-                    "generate" => self.handle_generate(node_msg).await?,
-                    "topology" => self.handle_topology(node_msg)?,
-                    "broadcast" => self.handle_broadcast(node_msg).await?,
-                    "broadcast_ok" => self.handle_broadcast_ok(node_msg).await?,
-                    "read" => self.handle_read(node_msg).await?,
-                    "add" => self.handle_add(node_msg).await?,
-                    "share_counter_state" => self.handle_share_counter_state(node_msg).await,
-                    "send" => self.handle_send(node_msg)?,
-                    "poll" => self.handle_poll(node_msg)?,
-                    "commit_offsets" => self.handle_commit_offsets(node_msg)?,
-                    "list_committed_offsets" => self.handle_list_commited_offsets(node_msg)?,
-                    _ => unimplemented!()
-                }
-            },
+        if self.kv_kafka.is_some() {
+            return self.reply_unsupported(node_msg.dest, node_msg.src, msg_id, "batch sends")
         }
 
-        Ok(())
-    }
+        //this node coordinates the batch, its own keys take part like any other owner's
+        let (local, mut parts) = self.split_by_owner(msgs);
+        if !local.is_empty() {
+            parts.insert(node_msg.dest.clone(), local);
+        }
+        //msg ids start over when the node restarts, owners may still remember batches of the previous run
+        let txn_id = format!("{}-{}", node_msg.dest, uuid::Uuid::new_v4().simple());
+        let rpc = self.rpc.clone();
+        let output_sender = self.output_sender.clone();
+        let batch_outcomes = self.batch_outcomes.clone();
+
+        tokio::spawn(async move {
+            let mut offsets = HashMap::new();
+            let mut failure = None;
+            for (owner, msgs) in &parts {
+                let prepare = |msg_id| MessageBody::PrepareBatch {msg_id, txn_id: txn_id.clone(), msgs: msgs.clone()};
+                match rpc.call(owner, prepare).await {
+                    Ok(MessageBody::PrepareBatchOk {offsets: owner_offsets, ..}) => offsets.extend(owner_offsets),
+                    Ok(_) => {
+                        failure = Some((owner.clone(), RpcError::Remote {code: 13, text: String::from("unexpected reply to prepare")}));
+                        break
+                    },
+                    Err(e) => {
+                        failure = Some((owner.clone(), e));
+                        break
+                    }
+                }
+            }
 
-    pub async fn stdout_writer(mut rx: mpsc::UnboundedReceiver<MessageForm>) {
-        let mut stdout = tokio::io::stdout();
-        while let Some(msg) = rx.recv().await {
-            let output = match MaelstromMessage::from_deserialized_msg(msg) {
-                Ok(ser_msg) => ser_msg,
-                Err(_) => {
-                    eprintln!("error occur while serializing msg...");
-                    continue
+            //an owner that stopped waiting may have asked for the outcome already, the batch is aborted then
+            let commit = *batch_outcomes.lock().await.entry(txn_id.clone()).or_insert(failure.is_none());
+            let body = match failure {
+                None if commit => MessageBody::SendBatchOk {in_reply_to: msg_id, offsets},
+                None => MessageBody::Error {
+                    in_reply_to: msg_id,
+                    code: 14,
+                    text: String::from("batch aborted, a key owner stopped waiting for its outcome")
+                },
+                Some((owner, e)) => MessageBody::Error {
+                    in_reply_to: msg_id,
+                    code: rpc_error_code(&e),
+                    text: format!("batch aborted, key owner {owner} failed: {e}")
                 }
             };
+            let msg = Message {
+                src: node_msg.dest,
+                dest: node_msg.src,
+                body
+            };
 
-            let mut bytes = output.into_bytes();
-            bytes.push(b'\n');
+            //an aborted batch is never applied, so the client doesn't wait for the owners to hear about it
+            if !commit {
+                let _ = output_sender.send(msg.clone().into());
+            }
 
-            if let Err(e) = stdout.write_all(&bytes).await {
-                eprintln!("error writing to stdout: {e}");
-                continue;
+            //every owner hears the outcome, also the ones never reached, so a late prepare is refused
+            for owner in parts.keys() {
+                decide_batch(&rpc, owner, &txn_id, commit).await;
             }
 
-            // flush обязательно обрабатываем
-            if let Err(e) = stdout.flush().await {
-                eprintln!("error flushing stdout: {e}");
+            //a committed batch is acknowledged once every key shows it
+            if commit {
+                let _ = output_sender.send(msg.into());
             }
-        }
+        });
+
+        Ok(())
     }
 
-    pub async fn run(&mut self) -> tokio::io::Result<()> {
+    fn handle_prepare_batch(&mut self, node_msg: Message<MessageBody>) -> Result<(), Box<dyn Error>> {
+        let MessageBody::PrepareBatch {msg_id, txn_id, msgs} = node_msg.body else {
+            return Ok(())
+        };
 
-        let buf = tokio::io::BufReader::new(tokio::io::stdin());
-        let mut lines = buf.lines();
+        let body = match self.kafka.prepare_batch(txn_id.clone(), msgs) {
+            Ok(offsets) => MessageBody::PrepareBatchOk {in_reply_to: msg_id, offsets},
+            Err(e) => kafka_error_reply(msg_id, e)
+        };
 
-        while let Some(line) = lines.next_line().await? {
-            let maelstrom_msg = MaelstromMessage::from(line);
-            let des_message = match maelstrom_msg.to_deserialized_msg() {
-                Ok(des_msg) => des_msg,
-                Err(_) => {
-                    eprintln!("error occur while deserializing msg...");
-                    continue
+        //a coordinator that crashed or got cut off would leave the keys locked for good
+        if let MessageBody::PrepareBatchOk {..} = body {
+            let rpc = self.rpc.clone();
+            let (owner, coordinator) = (node_msg.dest.clone(), node_msg.src.clone());
+            tokio::spawn(async move {
+                tokio::time::sleep(BATCH_LEASE).await;
+                let ask = |msg_id| MessageBody::BatchOutcome {msg_id, txn_id: txn_id.clone()};
+                if let Ok(MessageBody::BatchOutcomeOk {commit, ..}) = rpc.call_until_answered(&coordinator, ask).await {
+                    //a no-op when the coordinator's decision arrived meanwhile
+                    decide_batch(&rpc, &owner, &txn_id, commit).await;
                 }
-            };
+            });
+        }
 
-            if let Err(e) = self.handle_message(des_message).await {
-                eprintln!("error occur while handling a message: {e}")
-            }
+        let msg = Message {
+            src: node_msg.dest,
+            dest: node_msg.src,
+            body
+        };
+        self.output_sender.send(msg.into()).map_err(|e| Box::new(e) as Box<dyn Error>)
+    }
 
+    fn handle_commit_batch(&mut self, node_msg: Message<MessageBody>) -> Result<(), Box<dyn Error>> {
+        let MessageBody::CommitBatch {msg_id, txn_id} = node_msg.body else {
+            return Ok(())
         };
-        Ok(())
+
+        let body = match self.kafka.commit_batch(txn_id) {
+            Ok(()) => MessageBody::CommitBatchOk {in_reply_to: msg_id},
+            Err(e) => kafka_error_reply(msg_id, e)
+        };
+
+        let msg = Message {
+            src: node_msg.dest,
+            dest: node_msg.src,
+            body
+        };
+        self.output_sender.send(msg.into()).map_err(|e| Box::new(e) as Box<dyn Error>)
+    }
+
+    // a batch this node hasn't decided yet is aborted, so the coordinator can't commit it after the owner gave up
+    async fn handle_batch_outcome(&mut self, node_msg: Message<MessageBody>) -> Result<(), Box<dyn Error>> {
+        let MessageBody::BatchOutcome {msg_id, txn_id} = node_msg.body else {
+            return Ok(())
+        };
+
+        let commit = *self.batch_outcomes.lock().await.entry(txn_id).or_insert(false);
+
+        let msg = Message {
+            src: node_msg.dest,
+            dest: node_msg.src,
+            body: MessageBody::BatchOutcomeOk {in_reply_to: msg_id, commit}
+        };
+        self.output_sender.send(msg.into()).map_err(|e| Box::new(e) as Box<dyn Error>)
     }
-}
 
+    fn handle_abort_batch(&mut self, node_msg: Message<MessageBody>) -> Result<(), Box<dyn Error>> {
+        let MessageBody::AbortBatch {msg_id, txn_id} = node_msg.body else {
+            return Ok(())
+        };
 
+        self.kafka.abort_batch(txn_id);
+
+        let msg = Message {
+            src: node_msg.dest,
+            dest: node_msg.src,
+            body: MessageBody::AbortBatchOk {in_reply_to: msg_id}
+        };
+        self.output_sender.send(msg.into()).map_err(|e| Box::new(e) as Box<dyn Error>)
+    }
 
+    fn handle_poll(&self, msg: Message<MessageBody>) -> Result<(), Box<dyn Error>> {
+        let MessageBody::Poll {msg_id, offsets, max_messages_per_key, max_messages, max_bytes} = msg.body else {
+            return Ok(())
+        };
+
+        let limits = PollLimits {max_messages_per_key, max_messages, max_bytes}.or(self.poll_limits);
+
+        if let Some(kv_kafka) = self.kv_kafka.clone() {
+            self.reply_when_ready(msg.dest, msg.src, msg_id, async move {
+                let (msgs, more) = kv_kafka.read_logs(offsets, &limits).await?;
+                Ok(MessageBody::PollOk {in_reply_to: msg_id, msgs, more})
+            });
+            return Ok(())
+        }
+
+        let (local, remote) = self.split_by_owner(offsets);
+        let (msgs, more) = self.kafka.read_logs(local, &limits);
+
+        let body = MessageBody::PollOk {
+            in_reply_to: msg_id,
+            msgs,
+            more
+        };
+
+        if !remote.is_empty() {
+            self.gather_from_owners(
+                (msg.dest, msg.src, msg_id),
+                remote,
+                body,
+                move |msg_id, offsets| MessageBody::Poll {
+                    msg_id,
+                    offsets,
+                    max_messages_per_key: limits.max_messages_per_key,
+                    max_messages: limits.max_messages,
+                    max_bytes: limits.max_bytes
+                },
+                //every owner fills its own budget, the merged answer is cut back to the totals
+                move |reply, owner_reply| {
+                    if let (MessageBody::PollOk {msgs, more, ..}, MessageBody::PollOk {msgs: owner_msgs, more: owner_more, ..}) = (reply, owner_reply) {
+                        msgs.extend(owner_msgs);
+                        *more |= owner_more;
+                        *more |= limits.trim(msgs);
+                    }
+                }
+            );
+            return Ok(())
+        }
+
+        let msg = Message {
+            src: String::from(&msg.dest),
+            dest: String::from(&msg.src),
+            body
+        };
+
+        self.output_sender.send(msg.into()).map_err(|e| Box::new(e) as Box<dyn Error>)
+    }
+
+    fn handle_commit_offsets(&mut self, node_msg: Message<MessageBody>) -> Result<(), Box<dyn Error>> {
+        let MessageBody::CommitOffsets {msg_id, offsets, group} = node_msg.body else {
+            return Ok(())
+        };
+
+        let group = group.unwrap_or_else(|| String::from(DEFAULT_GROUP));
+
+        if let Some(kv_kafka) = self.kv_kafka.clone() {
+            self.reply_when_ready(node_msg.dest, node_msg.src, msg_id, async move {
+                kv_kafka.commit_offsets(group, offsets).await?;
+                Ok(MessageBody::CommitOffsetsOk {in_reply_to: msg_id})
+            });
+            return Ok(())
+        }
+
+        let (local, remote) = self.split_by_owner(offsets);
+
+        //the remote offsets aren't committed either, so the client retries all of them
+        if let Err(e) = self.kafka.commit_offsets(group.clone(), local) {
+            let msg = Message {
+                src: node_msg.dest,
+                dest: node_msg.src,
+                body: kafka_error_reply(msg_id, KafkaError::Io(e))
+            };
+            return self.output_sender.send(msg.into()).map_err(|e| Box::new(e) as Box<dyn Error>)
+        }
+
+        let body = MessageBody::CommitOffsetsOk {
+            in_reply_to: msg_id,
+        };
+
+        if !remote.is_empty() {
+            self.gather_from_owners(
+                (node_msg.dest, node_msg.src, msg_id),
+                remote,
+                body,
+                move |msg_id, offsets| MessageBody::CommitOffsets {msg_id, offsets, group: Some(group.clone())},
+                |_, _| {}
+            );
+            return Ok(())
+        }
+
+        let msg = Message {
+            src: String::from(&node_msg.dest),
+            dest: String::from(&node_msg.src),
+            body
+        };
+
+        self.output_sender.send(msg.into()).map_err(|e| Box::new(e) as Box<dyn Error>)
+    }
+
+    fn handle_list_commited_offsets(&self, msg: Message<MessageBody>) -> Result<(), Box<dyn Error>> {
+        let MessageBody::ListCommittedOffsets {msg_id, keys, group} = msg.body else {
+            return Ok(())
+        };
+
+        let group = group.unwrap_or_else(|| String::from(DEFAULT_GROUP));
+
+        if let Some(kv_kafka) = self.kv_kafka.clone() {
+            self.reply_when_ready(msg.dest, msg.src, msg_id, async move {
+                let offsets = kv_kafka.get_commited_offsets(&group, keys).await?;
+                Ok(MessageBody::ListCommittedOffsetsOk {in_reply_to: msg_id, offsets})
+            });
+            return Ok(())
+        }
+
+        let keys = keys.into_iter().map(|key| (key, ())).collect::<HashMap<String, ()>>();
+        let (local, remote) = self.split_by_owner(keys);
+
+        let commited_offsets = self.kafka.get_commited_offsets(&group, local.into_keys().collect());
+
+        let body = MessageBody::ListCommittedOffsetsOk {
+            in_reply_to: msg_id,
+            offsets: commited_offsets
+        };
+
+        if !remote.is_empty() {
+            self.gather_from_owners(
+                (msg.dest, msg.src, msg_id),
+                remote,
+                body,
+                move |msg_id, keys| MessageBody::ListCommittedOffsets {msg_id, keys: keys.into_keys().collect(), group: Some(group.clone())},
+                |reply, owner_reply| {
+                    if let (MessageBody::ListCommittedOffsetsOk {offsets, ..}, MessageBody::ListCommittedOffsetsOk {offsets: owner_offsets, ..}) = (reply, owner_reply) {
+                        offsets.extend(owner_offsets);
+                    }
+                }
+            );
+            return Ok(())
+        }
+
+        let msg = Message {
+            src: String::from(&msg.dest),
+            dest: String::from(&msg.src),
+            body
+        };
+
+        self.output_sender.send(msg.into()).map_err(|e| Box::new(e) as Box<dyn Error>)
+    }
+
+    fn handle_list_keys(&self, msg: Message<MessageBody>) -> Result<(), Box<dyn Error>> {
+        let MessageBody::ListKeys {msg_id} = msg.body else {
+            return Ok(())
+        };
+
+        if self.kv_kafka.is_some() {
+            return self.reply_unsupported(msg.dest, msg.src, msg_id, "admin requests")
+        }
+
+        let body = MessageBody::ListKeysOk {in_reply_to: msg_id, keys: self.kafka.keys()};
+
+        //a peer only asks for the keys this node owns
+        let remote = self.other_nodes();
+        if !self.is_peer(&msg.src) && !remote.is_empty() {
+            self.gather_from_owners(
+                (msg.dest, msg.src, msg_id),
+                remote,
+                body,
+                |msg_id, ()| MessageBody::ListKeys {msg_id},
+                |reply, owner_reply| {
+                    if let (MessageBody::ListKeysOk {keys, ..}, MessageBody::ListKeysOk {keys: owner_keys, ..}) = (reply, owner_reply) {
+                        keys.extend(owner_keys);
+                        keys.sort();
+                    }
+                }
+            );
+            return Ok(())
+        }
+
+        let msg = Message {
+            src: String::from(&msg.dest),
+            dest: String::from(&msg.src),
+            body
+        };
+
+        self.output_sender.send(msg.into()).map_err(|e| Box::new(e) as Box<dyn Error>)
+    }
+
+    fn handle_describe_keys(&self, msg: Message<MessageBody>) -> Result<(), Box<dyn Error>> {
+        let MessageBody::DescribeKeys {msg_id, keys} = msg.body else {
+            return Ok(())
+        };
+
+        if self.kv_kafka.is_some() {
+            return self.reply_unsupported(msg.dest, msg.src, msg_id, "admin requests")
+        }
+
+        let keys = keys.into_iter().map(|key| (key, ())).collect::<HashMap<String, ()>>();
+        let (local, remote) = self.split_by_owner(keys);
+
+        let body = MessageBody::DescribeKeysOk {
+            in_reply_to: msg_id,
+            keys: self.kafka.describe_keys(local.into_keys().collect())
+        };
+
+        if !remote.is_empty() {
+            self.gather_from_owners(
+                (msg.dest, msg.src, msg_id),
+                remote,
+                body,
+                |msg_id, keys| MessageBody::DescribeKeys {msg_id, keys: keys.into_keys().collect()},
+                |reply, owner_reply| {
+                    if let (MessageBody::DescribeKeysOk {keys, ..}, MessageBody::DescribeKeysOk {keys: owner_keys, ..}) = (reply, owner_reply) {
+                        keys.extend(owner_keys);
+                    }
+                }
+            );
+            return Ok(())
+        }
+
+        let msg = Message {
+            src: String::from(&msg.dest),
+            dest: String::from(&msg.src),
+            body
+        };
+
+        self.output_sender.send(msg.into()).map_err(|e| Box::new(e) as Box<dyn Error>)
+    }
+
+    fn handle_list_groups(&self, msg: Message<MessageBody>) -> Result<(), Box<dyn Error>> {
+        let MessageBody::ListGroups {msg_id} = msg.body else {
+            return Ok(())
+        };
+
+        if self.kv_kafka.is_some() {
+            return self.reply_unsupported(msg.dest, msg.src, msg_id, "admin requests")
+        }
+
+        //offsets are committed at the key owners, which also know how far each key goes
+        let body = MessageBody::ListGroupsOk {in_reply_to: msg_id, groups: self.kafka.group_lags()};
+
+        let remote = self.other_nodes();
+        if !self.is_peer(&msg.src) && !remote.is_empty() {
+            self.gather_from_owners(
+                (msg.dest, msg.src, msg_id),
+                remote,
+                body,
+                |msg_id, ()| MessageBody::ListGroups {msg_id},
+                |reply, owner_reply| {
+                    if let (MessageBody::ListGroupsOk {groups, ..}, MessageBody::ListGroupsOk {groups: owner_groups, ..}) = (reply, owner_reply) {
+                        for (group, lags) in owner_groups {
+                            groups.entry(group).or_default().extend(lags);
+                        }
+                    }
+                }
+            );
+            return Ok(())
+        }
+
+        let msg = Message {
+            src: String::from(&msg.dest),
+            dest: String::from(&msg.src),
+            body
+        };
+
+        self.output_sender.send(msg.into()).map_err(|e| Box::new(e) as Box<dyn Error>)
+    }
+
+    fn handle_truncate_key(&mut self, msg: Message<MessageBody>) -> Result<(), Box<dyn Error>> {
+        let MessageBody::TruncateKey {msg_id, key, offset} = msg.body else {
+            return Ok(())
+        };
+
+        if self.kv_kafka.is_some() {
+            return self.reply_unsupported(msg.dest, msg.src, msg_id, "admin requests")
+        }
+
+        let owner = self.key_owner(&key);
+        if owner.ne(&msg.dest) {
+            let reply = MessageBody::TruncateKeyOk {in_reply_to: msg_id};
+            let remote = HashMap::from([(owner, (key, offset))]);
+            self.gather_from_owners(
+                (msg.dest, msg.src, msg_id),
+                remote,
+                reply,
+                |msg_id, (key, offset)| MessageBody::TruncateKey {msg_id, key, offset},
+                move |reply, owner_reply| {
+                    *reply = owner_reply;
+                    reply.set_in_reply_to(msg_id);
+                }
+            );
+            return Ok(())
+        }
+
+        let body = match self.kafka.truncate_key(&key, offset) {
+            Ok(()) => MessageBody::TruncateKeyOk {in_reply_to: msg_id},
+            Err(e) => kafka_error_reply(msg_id, KafkaError::Io(e))
+        };
+
+        let msg = Message {
+            src: String::from(&msg.dest),
+            dest: String::from(&msg.src),
+            body
+        };
+
+        self.output_sender.send(msg.into()).map_err(|e| Box::new(e) as Box<dyn Error>)
+    }
+
+    // every node writes the keys it holds, so a client asks each node for its own file
+    fn handle_export_snapshot(&self, msg: Message<MessageBody>) -> Result<(), Box<dyn Error>> {
+        let MessageBody::ExportSnapshot {msg_id, path} = msg.body else {
+            return Ok(())
+        };
+
+        if self.kv_kafka.is_some() {
+            return self.reply_unsupported(msg.dest, msg.src, msg_id, "snapshots")
+        }
+        let Some(snapshot_dir) = &self.kafka_snapshot_dir else {
+            return self.reply_error(msg.dest, msg.src, msg_id, 10, String::from("snapshots need a snapshot dir"))
+        };
+
+        //a relative path without `..` can't reach out of the snapshot dir
+        let file = Path::new(&path);
+        if path.is_empty() || !file.components().all(|component| matches!(component, Component::Normal(_))) {
+            return self.reply_error(msg.dest, msg.src, msg_id, 12, format!("snapshot path {path} must stay inside the snapshot dir"))
+        }
+
+        let body = match self.kafka.export_snapshot(&snapshot_dir.join(file)) {
+            Ok(()) => MessageBody::ExportSnapshotOk {in_reply_to: msg_id},
+            Err(e) => MessageBody::Error {
+                in_reply_to: msg_id,
+                code: 13,
+                text: format!("error occur while writing snapshot {path}: {e}")
+            }
+        };
+
+        let msg = Message {
+            src: String::from(&msg.dest),
+            dest: String::from(&msg.src),
+            body
+        };
+
+        self.output_sender.send(msg.into()).map_err(|e| Box::new(e) as Box<dyn Error>)
+    }
+
+    async fn handle_topic_send(&mut self, msg: Message<MessageBody>) -> Result<(), Box<dyn Error>> {
+        let MessageBody::TopicSend {msg_id, topic, msg: payload, key, partition} = msg.body else {
+            return Ok(())
+        };
+
+        let partition = match self.topics.pick_partition(&topic, key.as_deref(), partition) {
+            Ok(partition) => partition,
+            Err(text) => return self.reply_error(msg.dest, msg.src, msg_id, 12, text)
+        };
+
+        let key = partition_key(&topic, partition);
+        self.relay_to_self(
+            (msg.dest, msg.src, msg_id),
+            |msg_id| MessageBody::Send {msg_id, key, msg: payload, producer_id: None, seq: None},
+            Node::handle_send,
+            move |reply| match reply {
+                MessageBody::SendOk {in_reply_to, offset} => MessageBody::TopicSendOk {in_reply_to, partition, offset},
+                other => other
+            }
+        ).await
+    }
+
+    async fn handle_topic_poll(&mut self, msg: Message<MessageBody>) -> Result<(), Box<dyn Error>> {
+        let MessageBody::TopicPoll {msg_id, offsets, max_messages_per_key, max_messages, max_bytes} = msg.body else {
+            return Ok(())
+        };
+
+        let (offsets, partitions) = match self.partition_keys(offsets) {
+            Ok(keys) => keys,
+            Err(text) => return self.reply_error(msg.dest, msg.src, msg_id, 12, text)
+        };
+
+        self.relay_to_self(
+            (msg.dest, msg.src, msg_id),
+            |msg_id| MessageBody::Poll {msg_id, offsets, max_messages_per_key, max_messages, max_bytes},
+            |node, msg| node.handle_poll(msg),
+            move |reply| match reply {
+                MessageBody::PollOk {in_reply_to, msgs, more} => MessageBody::TopicPollOk {in_reply_to, msgs: by_topic(&partitions, msgs), more},
+                other => other
+            }
+        ).await
+    }
+
+    async fn handle_topic_commit_offsets(&mut self, msg: Message<MessageBody>) -> Result<(), Box<dyn Error>> {
+        let MessageBody::TopicCommitOffsets {msg_id, offsets, group} = msg.body else {
+            return Ok(())
+        };
+
+        let (offsets, _) = match self.partition_keys(offsets) {
+            Ok(keys) => keys,
+            Err(text) => return self.reply_error(msg.dest, msg.src, msg_id, 12, text)
+        };
+
+        self.relay_to_self(
+            (msg.dest, msg.src, msg_id),
+            |msg_id| MessageBody::CommitOffsets {msg_id, offsets, group},
+            Node::handle_commit_offsets,
+            |reply| match reply {
+                MessageBody::CommitOffsetsOk {in_reply_to} => MessageBody::TopicCommitOffsetsOk {in_reply_to},
+                other => other
+            }
+        ).await
+    }
+
+    async fn handle_topic_list_committed_offsets(&mut self, msg: Message<MessageBody>) -> Result<(), Box<dyn Error>> {
+        let MessageBody::TopicListCommittedOffsets {msg_id, topics, group} = msg.body else {
+            return Ok(())
+        };
+
+        let all_partitions = topics.into_iter().map(|topic| {
+            let partitions = (0..self.topics.config(&topic).partitions).map(|partition| (partition, ())).collect();
+            (topic, partitions)
+        }).collect::<HashMap<String, HashMap<usize, ()>>>();
+        let (keys, partitions) = match self.partition_keys(all_partitions) {
+            Ok(keys) => keys,
+            Err(text) => return self.reply_error(msg.dest, msg.src, msg_id, 12, text)
+        };
+
+        self.relay_to_self(
+            (msg.dest, msg.src, msg_id),
+            |msg_id| MessageBody::ListCommittedOffsets {msg_id, keys: keys.into_keys().collect(), group},
+            |node, msg| node.handle_list_commited_offsets(msg),
+            move |reply| match reply {
+                MessageBody::ListCommittedOffsetsOk {in_reply_to, offsets} => MessageBody::TopicListCommittedOffsetsOk {in_reply_to, offsets: by_topic(&partitions, offsets)},
+                other => other
+            }
+        ).await
+    }
+
+    // applied and answered locally, so transactions go on under partitions, peers catch up once they heal
+    fn handle_txn(&mut self, msg: Message<MessageBody>) -> Result<(), Box<dyn Error>> {
+        let MessageBody::Txn {msg_id, txn} = msg.body else {
+            return Ok(())
+        };
+
+        if let Some(list_append) = self.list_append.clone() {
+            self.reply_when_ready(msg.dest, msg.src, msg_id, async move {
+                let txn = list_append.transact(txn).await?;
+                Ok(MessageBody::TxnOk {in_reply_to: msg_id, txn})
+            });
+            return Ok(())
+        }
+
+        if txn.iter().any(|op| op.0 == OpKind::Append) {
+            return self.reply_error(msg.dest, msg.src, msg_id, 10, String::from("registers are only read and written"))
+        }
+
+        let (txn, replicated) = self.txn_store.as_mut().unwrap().apply(txn);
+        if !replicated.writes.is_empty() {
+            self.replicate_txn(replicated);
+        }
+
+        let msg = Message {
+            src: String::from(&msg.dest),
+            dest: String::from(&msg.src),
+            body: MessageBody::TxnOk {in_reply_to: msg_id, txn}
+        };
+
+        self.output_sender.send(msg.into()).map_err(|e| Box::new(e) as Box<dyn Error>)
+    }
+
+    // every peer has a single queue, so an unreachable peer holds one request in flight however many transactions wait
+    fn replicate_txn(&mut self, txn: ReplicatedTxn) {
+        for peer in self.other_nodes().into_keys() {
+            let queue = self.txn_replication.entry(peer.clone()).or_insert_with(|| {
+                let (tx, rx) = mpsc::unbounded_channel();
+                tokio::spawn(replicate_to_peer(self.rpc.clone(), peer, rx));
+                tx
+            });
+            let _ = queue.send(txn.clone());
+        }
+    }
+
+    fn handle_replicate_txn(&mut self, msg: Message<MessageBody>) -> Result<(), Box<dyn Error>> {
+        let MessageBody::ReplicateTxn {msg_id, txns} = msg.body else {
+            return Ok(())
+        };
+
+        let txn_store = self.txn_store.as_mut().unwrap();
+        for txn in txns {
+            txn_store.merge(txn);
+        }
+
+        let msg = Message {
+            src: String::from(&msg.dest),
+            dest: String::from(&msg.src),
+            body: MessageBody::ReplicateTxnOk {in_reply_to: msg_id}
+        };
+
+        self.output_sender.send(msg.into()).map_err(|e| Box::new(e) as Box<dyn Error>)
+    }
+
+    // lin-kv requests are proposed to raft and answered once applied
+    // a node that isn't the leader hands them to the leader it knows of and relays the answer
+    fn handle_lin_kv(&self, msg: Message<MessageBody>) -> Result<(), Box<dyn Error>> {
+        let (MessageBody::Read {msg_id, ..} | MessageBody::Write {msg_id, ..} | MessageBody::Cas {msg_id, ..}) = msg.body else {
+            return Ok(())
+        };
+        let Some(raft) = self.raft.clone() else {
+            return self.reply_error(msg.dest, msg.src, msg_id, 10, String::from("lin-kv requests need raft"))
+        };
+        let Some(command) = KvCommand::from_request(msg.body) else {
+            return self.reply_error(msg.dest, msg.src, msg_id, 12, String::from("lin-kv reads need a key"))
+        };
+
+        let rpc = self.rpc.clone();
+        let output_sender = self.output_sender.clone();
+        tokio::spawn(async move {
+            let body = command.serve(&raft, &rpc, msg_id).await;
+
+            let msg = Message {
+                src: msg.dest,
+                dest: msg.src,
+                body
+            };
+            let _ = output_sender.send(msg.into());
+        });
+
+        Ok(())
+    }
+
+    async fn handle_raft(&self, msg: Message<MessageBody>) -> Result<(), Box<dyn Error>> {
+        let Some(raft) = &self.raft else {
+            return Ok(())
+        };
+        let Some(body) = raft.on_message(msg.body).await else {
+            return Ok(())
+        };
+
+        let msg = Message {
+            src: msg.dest,
+            dest: msg.src,
+            body
+        };
+        self.output_sender.send(msg.into()).map_err(|e| Box::new(e) as Box<dyn Error>)
+    }
+
+    async fn handle_message(&mut self, msg_form: MessageForm) -> Result<(), Box<dyn Error>> {
+
+        match msg_form {
+            MessageForm::NodeMessage(node_msg) => {
+                //replies to our own requests never reach the handlers
+                let Some(node_msg) = self.rpc.resolve(node_msg).await else {
+                    return Ok(())
+                };
+                //partitions are only reached through topic requests, the nodes themselves use their keys as is
+                let from_node = self.node_ids.iter().flatten().any(|node| node.eq(&node_msg.src));
+                if !from_node && let Some(key) = node_msg.body.kafka_keys().into_iter().find(|key| is_partition_key(key)) {
+                    let text = format!("key {key} is reserved for topic partitions");
+                    let msg_id = *node_msg.msg_id().unwrap();
+                    return self.reply_error(node_msg.dest, node_msg.src, msg_id, 12, text)
+                }
+
+                let msg_type = node_msg.typ();
+                if self.id.is_none() && msg_type != "init" {
+                    eprintln!("ignoring {msg_type} message from {}, the node is not initialized", node_msg.src);
+                    return Ok(())
+                }
+                match msg_type.as_str() {
+                    "init" => self.handle_init(node_msg).await?,
+                    "echo" => self.handle_echo(node_msg)?,
+                    "generate" => self.handle_generate(node_msg).await?,
+                    "topology" => self.handle_topology(node_msg).await?,
+                    "broadcast" => self.handle_broadcast(node_msg).await?,
+                    "i_have" => self.handle_ihave(node_msg).await,
+                    "graft" => self.handle_graft(node_msg).await,
+                    "prune" => self.handle_prune(node_msg).await,
+                    "read" if self.raft.is_some() => self.handle_lin_kv(node_msg)?,
+                    "read" => self.handle_read(node_msg).await?,
+                    "write" | "cas" => self.handle_lin_kv(node_msg)?,
+                    "request_vote" | "append_entries" | "install_snapshot" => self.handle_raft(node_msg).await?,
+                    "add" => self.handle_add(node_msg).await?,
+                    "share_counter_state" => self.handle_share_counter_state(node_msg).await,
+                    "send" => self.handle_send(node_msg)?,
+                    "send_batch" => self.handle_send_batch(node_msg)?,
+                    "prepare_batch" => self.handle_prepare_batch(node_msg)?,
+                    "commit_batch" => self.handle_commit_batch(node_msg)?,
+                    "abort_batch" => self.handle_abort_batch(node_msg)?,
+                    "batch_outcome" => self.handle_batch_outcome(node_msg).await?,
+                    "list_keys" => self.handle_list_keys(node_msg)?,
+                    "describe_keys" => self.handle_describe_keys(node_msg)?,
+                    "list_groups" => self.handle_list_groups(node_msg)?,
+                    "truncate_key" => self.handle_truncate_key(node_msg)?,
+                    "export_snapshot" => self.handle_export_snapshot(node_msg)?,
+                    "txn" => self.handle_txn(node_msg)?,
+                    "replicate_txn" => self.handle_replicate_txn(node_msg)?,
+                    "topic_send" => self.handle_topic_send(node_msg).await?,
+                    "topic_poll" => self.handle_topic_poll(node_msg).await?,
+                    "topic_commit_offsets" => self.handle_topic_commit_offsets(node_msg).await?,
+                    "topic_list_committed_offsets" => self.handle_topic_list_committed_offsets(node_msg).await?,
+                    "poll" => self.handle_poll(node_msg)?,
+                    "commit_offsets" => self.handle_commit_offsets(node_msg)?,
+                    "list_committed_offsets" => self.handle_list_commited_offsets(node_msg)?,
+                    _ => eprintln!("ignoring unexpected {msg_type} message from {}", node_msg.src)
+                }
+            },
+            MessageForm::KvMessage(kv_msg) => {
+                if let Some(kv_client) = self.kv_clients.get(&kv_msg.src) {
+                    kv_client.resolve(kv_msg.body).await;
+                }
+            },
+        }
+
+        Ok(())
+    }
+
+    pub async fn stdout_writer(mut rx: mpsc::UnboundedReceiver<MessageForm>, loopback: mpsc::UnboundedSender<MessageForm>, address: Arc<OnceLock<String>>) {
+        let mut stdout = tokio::io::stdout();
+        while let Some(msg) = rx.recv().await {
+            //messages to the node itself never leave the process
+            if let MessageForm::NodeMessage(node_msg) = &msg && address.get().is_some_and(|node_id| node_id.eq(&node_msg.dest)) {
+                let _ = loopback.send(msg);
+                continue
+            }
+
+            let output = match MaelstromMessage::from_deserialized_msg(msg) {
+                Ok(ser_msg) => ser_msg,
+                Err(_) => {
+                    eprintln!("error occur while serializing msg...");
+                    continue
+                }
+            };
+
+            let mut bytes = output.into_bytes();
+            bytes.push(b'\n');
+
+            if let Err(e) = stdout.write_all(&bytes).await {
+                eprintln!("error writing to stdout: {e}");
+                continue;
+            }
+
+            // flush обязательно обрабатываем
+            if let Err(e) = stdout.flush().await {
+                eprintln!("error flushing stdout: {e}");
+            }
+        }
+    }
+
+    pub async fn run(&mut self) -> tokio::io::Result<()> {
+
+        let buf = tokio::io::BufReader::new(tokio::io::stdin());
+        let mut lines = buf.lines();
+
+        loop {
+            let des_message = tokio::select! {
+                line = lines.next_line() => {
+                    let Some(line) = line? else { break };
+                    match MaelstromMessage::from(line).to_deserialized_msg() {
+                        Ok(des_msg) => des_msg,
+                        Err(_) => {
+                            eprintln!("error occur while deserializing msg...");
+                            continue
+                        }
+                    }
+                },
+                Some(own_msg) = self.inbox.recv() => own_msg
+            };
+
+            if let Err(e) = self.handle_message(des_message).await {
+                eprintln!("error occur while handling a message: {e}")
+            }
+
+        };
+        Ok(())
+    }
+}
+
+// why a node could not start, reported in the reply to `init`
+#[derive(Debug)]
+enum InitError {
+    NotInCluster {node_id: String},
+    Ids(IdError),
+    Snapshot(SnapshotError),
+    KafkaLog(io::Error),
+    Retention(io::Error),
+    RaftLog(io::Error)
+}
+
+impl Display for InitError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InitError::NotInCluster {node_id} => write!(f, "node {node_id} is not among the cluster's node ids"),
+            InitError::Ids(e) => write!(f, "{e}"),
+            InitError::Snapshot(e) => write!(f, "error occur while restoring kafka snapshot: {e}"),
+            InitError::KafkaLog(e) => write!(f, "error occur while recovering kafka logs: {e}"),
+            InitError::Retention(e) => write!(f, "error occur while applying kafka retention: {e}"),
+            InitError::RaftLog(e) => write!(f, "error occur while recovering raft log: {e}")
+        }
+    }
+}
+
+impl Error for InitError {}
+
+impl InitError {
+    fn code(&self) -> u32 {
+        match self {
+            InitError::NotInCluster {..} | InitError::Ids(IdError::NodeIndex {..}) => 12,
+            InitError::Ids(IdError::State(_)) | InitError::Snapshot(_) | InitError::KafkaLog(_) | InitError::Retention(_) | InitError::RaftLog(_) => 13
+        }
+    }
+}
+
+impl From<IdError> for InitError {
+    fn from(e: IdError) -> Self {
+        InitError::Ids(e)
+    }
+}
+
+// peer_id -> values it sent us, acked or announced
+// a value every peer holds is forgotten, nothing gossips it to them anymore
+#[derive(Default)]
+struct PeerKnowledge {
+    peers: usize,
+    known: HashMap<String, HashSet<Payload>>
+}
+
+impl PeerKnowledge {
+    fn knows(&self, peer: &str, message: &Payload) -> bool {
+        self.known.get(peer).is_some_and(|known| known.contains(message))
+    }
+
+    fn learn(&mut self, peer: &str, messages: impl IntoIterator<Item = Payload>) {
+        for message in messages {
+            self.known.entry(String::from(peer)).or_default().insert(message.clone());
+            let holders = self.known.values().filter(|known| known.contains(&message)).count();
+            if holders >= self.peers {
+                for known in self.known.values_mut() {
+                    known.remove(&message);
+                }
+            }
+        }
+    }
+}
+
+// regroups per-key values of partition keys by their topic & partition
+fn by_topic<V>(partitions: &PartitionKeys, by_key: HashMap<String, V>) -> HashMap<String, HashMap<usize, V>> {
+    let mut grouped: HashMap<String, HashMap<usize, V>> = HashMap::new();
+    for (key, value) in by_key {
+        if let Some((topic, partition)) = partitions.get(&key) {
+            grouped.entry(topic.clone()).or_default().insert(*partition, value);
+        }
+    }
+    grouped
+}
+
+// topic partitions are placed by `Topics`, every other key by its hash
+fn key_owner_in<'a>(nodes: &'a [String], topics: &Topics, key: &str) -> &'a String {
+    if let Some(owner) = topics.owner(nodes, key) {
+        return owner
+    }
+
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    &nodes[(hasher.finish() % nodes.len() as u64) as usize]
+}
+
+// delivers a batch outcome to an owner, retrying until it answers
+// an owner failing to apply the decision hears it again, its batch stays prepared until it does
+async fn decide_batch(rpc: &Rpc, owner: &str, txn_id: &str, commit: bool) {
+    let decision = |msg_id| match commit {
+        true => MessageBody::CommitBatch {msg_id, txn_id: String::from(txn_id)},
+        false => MessageBody::AbortBatch {msg_id, txn_id: String::from(txn_id)}
+    };
+    while rpc.call_until_answered(owner, decision).await.is_err() {
+        tokio::time::sleep(RPC_TIMEOUT).await;
+    }
+}
+
+// kafka errors clients can act on become error replies, storage failures are the node's own
+fn kafka_error_reply(in_reply_to: u32, e: KafkaError) -> MessageBody {
+    let code = match e {
+        //the write may or may not have reached the disk
+        KafkaError::Io(_) => 13,
+        KafkaError::KeyLocked {..} => 11,
+        KafkaError::BatchFinished {..} => 14,
+        KafkaError::StaleSequence {..} => 22
+    };
+    MessageBody::Error {in_reply_to, code, text: e.to_string()}
+}
+
+// sends the queued transactions to `peer` in order, whatever queued up while a request was in flight goes in the next one
+async fn replicate_to_peer(rpc: Rpc, peer: String, mut queue: mpsc::UnboundedReceiver<ReplicatedTxn>) {
+    while let Some(txn) = queue.recv().await {
+        let mut txns = vec![txn];
+        while let Ok(txn) = queue.try_recv() {
+            txns.push(txn);
+        }
+
+        let replicate = |msg_id| MessageBody::ReplicateTxn {msg_id, txns: txns.clone()};
+        let _ = rpc.call_until_answered(&peer, replicate).await;
+    }
+}
+
+// unreachable owners are reported to clients as temporarily unavailable
+fn rpc_error_code(e: &RpcError) -> u32 {
+    match e {
+        RpcError::Timeout => 11,
+        RpcError::Remote {code, ..} => *code
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // an initialized n0 of n0..n3, whatever it sends ends up in the receiver
+    async fn started() -> (Node, mpsc::UnboundedReceiver<MessageForm>) {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut node = Node::new().await;
+        node.output_sender = tx.clone();
+        node.rpc = Rpc::new(tx);
+        let node_ids = ["n0", "n1", "n2", "n3"].iter().map(|node| String::from(*node)).collect();
+        let init = Message {src: String::from("c0"), dest: String::from("n0"), body: MessageBody::Init {msg_id: 1, node_id: String::from("n0"), node_ids}};
+        node.handle_message(init.into()).await.unwrap();
+        assert!(matches!(rx.try_recv(), Ok(MessageForm::NodeMessage(Message {body: MessageBody::InitOk {..}, ..}))));
+        (node, rx)
+    }
+
+    async fn receive(node: &mut Node, src: &str, body: MessageBody) {
+        let msg = Message {src: String::from(src), dest: String::from("n0"), body};
+        node.handle_message(msg.into()).await.unwrap();
+    }
+
+    // peer -> msg_id of every broadcast of `value` sent to it so far
+    async fn gossiped(rx: &mut mpsc::UnboundedReceiver<MessageForm>, value: u32) -> HashMap<String, Vec<u32>> {
+        //sends happen on spawned tasks
+        tokio::task::yield_now().await;
+        let mut gossiped: HashMap<String, Vec<u32>> = HashMap::new();
+        while let Ok(MessageForm::NodeMessage(msg)) = rx.try_recv() {
+            if let MessageBody::Broadcast {msg_id, message} = msg.body && message == Payload::from(value) {
+                gossiped.entry(msg.dest).or_default().push(msg_id);
+            }
+        }
+        gossiped
+    }
+
+    fn peers(gossiped: &HashMap<String, Vec<u32>>) -> Vec<&str> {
+        let mut peers = gossiped.keys().map(|peer| peer.as_str()).collect::<Vec<&str>>();
+        peers.sort();
+        peers
+    }
+
+    #[tokio::test]
+    async fn value_is_not_gossiped_back_to_peers_known_to_hold_it() {
+        let (mut node, mut rx) = started().await;
+
+        //sent by a peer
+        receive(&mut node, "n1", MessageBody::Broadcast {msg_id: 1, message: Payload::from(1)}).await;
+        assert_eq!(peers(&gossiped(&mut rx, 1).await), vec!["n2", "n3"]);
+
+        //announced in a peer's digest
+        receive(&mut node, "n2", MessageBody::IHave {messages: vec![Payload::from(2)]}).await;
+        receive(&mut node, "c1", MessageBody::Broadcast {msg_id: 1, message: Payload::from(2)}).await;
+        assert_eq!(peers(&gossiped(&mut rx, 2).await), vec!["n1", "n3"]);
+
+        //acked by a peer, the retries only go to the others
+        receive(&mut node, "c1", MessageBody::Broadcast {msg_id: 2, message: Payload::from(3)}).await;
+        let first = gossiped(&mut rx, 3).await;
+        assert_eq!(peers(&first), vec!["n1", "n2", "n3"]);
+        receive(&mut node, "n1", MessageBody::BroadcastOk {in_reply_to: first["n1"][0]}).await;
+        tokio::time::sleep(tokio::time::Duration::from_millis(400)).await;
+        assert_eq!(peers(&gossiped(&mut rx, 3).await), vec!["n2", "n3"]);
+    }
+
+    #[tokio::test]
+    async fn value_every_peer_holds_is_forgotten() {
+        let (mut node, _rx) = started().await;
+
+        receive(&mut node, "n1", MessageBody::IHave {messages: vec![Payload::from(1), Payload::from(2)]}).await;
+        receive(&mut node, "n2", MessageBody::IHave {messages: vec![Payload::from(1)]}).await;
+        receive(&mut node, "n3", MessageBody::IHave {messages: vec![Payload::from(1)]}).await;
+
+        let knowledge = node.peer_knowledge.lock().await;
+        assert!(knowledge.known.values().all(|known| !known.contains(&Payload::from(1))));
+        assert!(knowledge.knows("n1", &Payload::from(2)));
+    }
+
+    #[tokio::test]
+    async fn init_with_more_nodes_than_snowflake_ids_fit_is_refused() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut node = Node::new().await;
+        node.output_sender = tx;
+        let node_ids = (0..1100).map(|n| format!("n{n}")).collect();
+        let init = Message {src: String::from("c0"), dest: String::from("n1050"), body: MessageBody::Init {msg_id: 1, node_id: String::from("n1050"), node_ids}};
+        node.handle_message(init.into()).await.unwrap();
+
+        assert!(matches!(rx.try_recv(), Ok(MessageForm::NodeMessage(Message {body: MessageBody::Error {in_reply_to: 1, code: 12, ..}, ..}))));
+        assert!(node.id.is_none());
+    }
+
+    #[tokio::test]
+    async fn batch_an_owner_asked_about_while_preparing_is_aborted() {
+        let (mut node, mut rx) = started().await;
+        while rx.try_recv().is_ok() {}
+        let node_ids = node.node_ids.clone().unwrap();
+        let key = (0..).map(|k: u32| k.to_string()).find(|key| key_owner_in(&node_ids, &node.topics, key).eq("n1")).unwrap();
+
+        receive(&mut node, "c1", MessageBody::SendBatch {msg_id: 2, msgs: HashMap::from([(key.clone(), Payload::from(1))])}).await;
+        tokio::task::yield_now().await;
+        let Ok(MessageForm::NodeMessage(Message {body: MessageBody::PrepareBatch {msg_id: prepare_id, txn_id, ..}, ..})) = rx.try_recv() else {
+            panic!("batch wasn't prepared at the key owner")
+        };
+
+        //the owner gave up waiting before its prepare reply got through
+        receive(&mut node, "n1", MessageBody::BatchOutcome {msg_id: 7, txn_id: txn_id.clone()}).await;
+        assert!(matches!(rx.try_recv(), Ok(MessageForm::NodeMessage(Message {body: MessageBody::BatchOutcomeOk {in_reply_to: 7, commit: false}, ..}))));
+
+        receive(&mut node, "n1", MessageBody::PrepareBatchOk {in_reply_to: prepare_id, offsets: HashMap::from([(key, 0)])}).await;
+        tokio::task::yield_now().await;
+        assert!(matches!(rx.try_recv(), Ok(MessageForm::NodeMessage(Message {body: MessageBody::Error {in_reply_to: 2, code: 14, ..}, ..}))));
+        let Ok(MessageForm::NodeMessage(Message {dest, body: MessageBody::AbortBatch {txn_id: aborted, ..}, ..})) = rx.try_recv() else {
+            panic!("key owner wasn't told about the abort")
+        };
+        assert_eq!((dest.as_str(), aborted), ("n1", txn_id));
+    }
+
+    #[tokio::test]
+    async fn failed_offset_commit_is_answered_with_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        //the committed offsets can't be written to their temporary file
+        std::fs::create_dir_all(dir.path().join("n0").join("committed.tmp")).unwrap();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut node = Node::new().await.with_kafka_dir(dir.path().to_path_buf(), FsyncPolicy::Never);
+        node.output_sender = tx;
+        receive(&mut node, "c0", MessageBody::Init {msg_id: 1, node_id: String::from("n0"), node_ids: vec![String::from("n0")]}).await;
+        assert!(matches!(rx.try_recv(), Ok(MessageForm::NodeMessage(Message {body: MessageBody::InitOk {..}, ..}))));
+
+        receive(&mut node, "c0", MessageBody::CommitOffsets {msg_id: 2, offsets: HashMap::from([(String::from("k"), 0)]), group: None}).await;
+
+        assert!(matches!(rx.try_recv(), Ok(MessageForm::NodeMessage(Message {body: MessageBody::Error {in_reply_to: 2, code: 13, ..}, ..}))));
+    }
+
+    #[tokio::test]
+    async fn failed_truncation_is_answered_with_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut node = Node::new().await.with_kafka_dir(dir.path().to_path_buf(), FsyncPolicy::Never);
+        node.output_sender = tx;
+        receive(&mut node, "c0", MessageBody::Init {msg_id: 1, node_id: String::from("n0"), node_ids: vec![String::from("n0")]}).await;
+        receive(&mut node, "c0", MessageBody::Send {key: String::from("k"), msg: Payload::from(1), msg_id: 2, producer_id: None, seq: None}).await;
+        //the new start offset of key k can't be written to its temporary file
+        std::fs::create_dir_all(dir.path().join("n0").join("6b").join("start_offset.tmp")).unwrap();
+        while rx.try_recv().is_ok() {}
+
+        receive(&mut node, "c0", MessageBody::TruncateKey {msg_id: 3, key: String::from("k"), offset: 1}).await;
+
+        assert!(matches!(rx.try_recv(), Ok(MessageForm::NodeMessage(Message {body: MessageBody::Error {in_reply_to: 3, code: 13, ..}, ..}))));
+    }
+
+    #[tokio::test]
+    async fn unreadable_kafka_dir_is_an_init_error() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut node = Node::new().await.with_kafka_dir(file.path().to_path_buf(), FsyncPolicy::Never);
+        node.output_sender = tx;
+        let init = Message {src: String::from("c0"), dest: String::from("n0"), body: MessageBody::Init {msg_id: 1, node_id: String::from("n0"), node_ids: vec![String::from("n0")]}};
+        node.handle_message(init.into()).await.unwrap();
+
+        assert!(matches!(rx.try_recv(), Ok(MessageForm::NodeMessage(Message {body: MessageBody::Error {in_reply_to: 1, code: 13, ..}, ..}))));
+        assert!(node.id.is_none());
+    }
+}
@@ -0,0 +1,313 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, Mutex};
+use crate::message::{Message, MessageBody, MessageForm, Payload};
+
+// how long a missing value may stay announced-only before we graft
+const MISSING_TIMEOUT: Duration = Duration::from_millis(200);
+// how often queued lazy announcements are flushed to peers
+const LAZY_FLUSH_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum BroadcastMode {
+    // every new value is pushed to every other node
+    #[default]
+    Flood,
+    // values are pushed along a self-repairing spanning tree
+    Plumtree
+}
+
+struct PlumtreeState {
+    cur_node: String,
+    // peers that receive full values right away
+    eager_peers: HashSet<String>,
+    // peers that only receive `IHave` announcements
+    lazy_peers: HashSet<String>,
+    // value -> peers that announced it to us, in announcement order
+    missing: HashMap<Payload, Vec<String>>,
+    // value -> peer it first came from, that peer retrying an unacked push is no redundant edge
+    first_sender: HashMap<Payload, String>,
+    // peer -> values waiting to be announced to it
+    lazy_queue: HashMap<String, Vec<Payload>>,
+    out: Option<mpsc::UnboundedSender<MessageForm>>
+}
+
+impl PlumtreeState {
+    fn make_eager(&mut self, peer: &String) {
+        self.lazy_peers.remove(peer);
+        self.eager_peers.insert(peer.clone());
+    }
+
+    fn make_lazy(&mut self, peer: &String) {
+        self.eager_peers.remove(peer);
+        self.lazy_peers.insert(peer.clone());
+    }
+
+    fn send(&self, dest: &String, body: MessageBody) {
+        let Some(out) = &self.out else { return };
+
+        let msg = Message {
+            src: String::from(&self.cur_node),
+            dest: String::from(dest),
+            body
+        };
+
+        let _ = out.send(msg.into());
+    }
+}
+
+pub(crate) struct Plumtree {
+    state: Arc<Mutex<PlumtreeState>>
+}
+
+impl Plumtree {
+
+    pub fn new() -> Self {
+        Plumtree {
+            state: Arc::new(Mutex::new(PlumtreeState {
+                cur_node: String::new(),
+                eager_peers: HashSet::new(),
+                lazy_peers: HashSet::new(),
+                missing: HashMap::new(),
+                first_sender: HashMap::new(),
+                lazy_queue: HashMap::new(),
+                out: None
+            }))
+        }
+    }
+
+    // every other node starts out eager, the tree is pruned down from there
+    // the peers are set before it returns, so a topology arriving right after init restricts them
+    pub async fn init_plumtree(&mut self, cur_node: String, other_nodes: Vec<String>, out: mpsc::UnboundedSender<MessageForm>) {
+        let mut guard = self.state.lock().await;
+        guard.eager_peers = other_nodes.into_iter().filter(|node| cur_node.ne(node)).collect();
+        guard.cur_node = cur_node;
+        guard.out = Some(out);
+        drop(guard);
+
+        let state = self.state.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(LAZY_FLUSH_INTERVAL).await;
+
+                let mut guard = state.lock().await;
+                let queue = std::mem::take(&mut guard.lazy_queue);
+                for (peer, messages) in queue {
+                    if !messages.is_empty() {
+                        guard.send(&peer, MessageBody::IHave { messages });
+                    }
+                }
+            }
+        });
+    }
+
+    // restricts the initial tree to the neighbours maelstrom suggested
+    pub async fn set_neighbours(&mut self, neighbours: Vec<String>) {
+        let mut guard = self.state.lock().await;
+        let all_peers = guard.eager_peers.union(&guard.lazy_peers).cloned().collect::<Vec<String>>();
+        for peer in &all_peers {
+            if neighbours.contains(peer) {
+                guard.make_eager(peer);
+            } else {
+                guard.make_lazy(peer);
+            }
+        }
+    }
+
+    // called for a value seen for the first time, returns the peers it has to be pushed to
+    pub async fn on_new_message(&mut self, from: Option<&String>, message: &Payload) -> Vec<String> {
+        let mut guard = self.state.lock().await;
+        guard.missing.remove(message);
+
+        if let Some(from) = from {
+            guard.make_eager(from);
+            guard.first_sender.insert(message.clone(), from.clone());
+        }
+
+        let lazy_peers = guard.lazy_peers.iter().filter(|peer| Some(*peer) != from).cloned().collect::<Vec<String>>();
+        for peer in lazy_peers {
+            guard.lazy_queue.entry(peer).or_default().push(message.clone());
+        }
+
+        guard.eager_peers.iter().filter(|peer| Some(*peer) != from).cloned().collect()
+    }
+
+    // a peer pushed a value we already had, so its tree edge is redundant
+    // unless it is the peer we got the value from, resending because our ack didn't reach it in time
+    pub async fn on_duplicate_message(&mut self, from: &String, message: &Payload) {
+        let mut guard = self.state.lock().await;
+        if guard.first_sender.get(message) == Some(from) {
+            return
+        }
+        if guard.eager_peers.contains(from) {
+            guard.make_lazy(from);
+            guard.send(from, MessageBody::Prune {});
+        }
+    }
+
+    pub async fn on_prune(&mut self, from: &String) {
+        self.state.lock().await.make_lazy(from);
+    }
+
+    // `unknown` must only contain values this node does not hold yet
+    pub async fn on_ihave(&mut self, from: &String, unknown: Vec<Payload>) {
+        let mut guard = self.state.lock().await;
+        for message in unknown {
+            let announcers = guard.missing.entry(message.clone()).or_default();
+            let first_announcement = announcers.is_empty();
+            if !announcers.contains(from) {
+                announcers.push(from.clone());
+            }
+
+            if first_announcement {
+                Plumtree::schedule_graft(self.state.clone(), message);
+            }
+        }
+    }
+
+    // a peer asked us to become its parent again
+    pub async fn on_graft(&mut self, from: &String) {
+        self.state.lock().await.make_eager(from);
+    }
+
+    fn schedule_graft(state: Arc<Mutex<PlumtreeState>>, message: Payload) {
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(MISSING_TIMEOUT).await;
+
+                let mut guard = state.lock().await;
+                let Some(announcers) = guard.missing.get_mut(&message) else { break };
+
+                if announcers.is_empty() {
+                    guard.missing.remove(&message);
+                    break
+                }
+
+                //asking the next announcer in line, tree gets repaired through it
+                let peer = announcers.remove(0);
+                guard.make_eager(&peer);
+                guard.send(&peer, MessageBody::Graft { messages: vec![message.clone()] });
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nodes() -> Vec<String> {
+        ["n0", "n1", "n2", "n3"].iter().map(|node| String::from(*node)).collect()
+    }
+
+    async fn started(out: mpsc::UnboundedSender<MessageForm>) -> Plumtree {
+        let mut plumtree = Plumtree::new();
+        plumtree.init_plumtree(String::from("n0"), nodes(), out).await;
+        plumtree
+    }
+
+    fn sent(rx: &mut mpsc::UnboundedReceiver<MessageForm>) -> Vec<(String, MessageBody)> {
+        let mut sent = Vec::new();
+        while let Ok(MessageForm::NodeMessage(msg)) = rx.try_recv() {
+            sent.push((msg.dest, msg.body));
+        }
+        sent
+    }
+
+    fn sorted(mut peers: Vec<String>) -> Vec<String> {
+        peers.sort();
+        peers
+    }
+
+    #[tokio::test]
+    async fn tree_starts_eager_and_follows_neighbours() {
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let mut plumtree = started(tx).await;
+
+        let eager = plumtree.on_new_message(None, &Payload::from(1)).await;
+        assert_eq!(sorted(eager), vec!["n1", "n2", "n3"]);
+
+        plumtree.set_neighbours(vec![String::from("n1")]).await;
+        let eager = plumtree.on_new_message(None, &Payload::from(2)).await;
+        assert_eq!(eager, vec!["n1"]);
+        let guard = plumtree.state.lock().await;
+        assert_eq!(guard.lazy_queue.get("n2"), Some(&vec![Payload::from(2)]));
+        assert_eq!(guard.lazy_queue.get("n3"), Some(&vec![Payload::from(2)]));
+    }
+
+    #[tokio::test]
+    async fn value_is_not_pushed_back_to_its_sender() {
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let mut plumtree = started(tx).await;
+
+        let eager = plumtree.on_new_message(Some(&String::from("n2")), &Payload::from(1)).await;
+        assert_eq!(sorted(eager), vec!["n1", "n3"]);
+    }
+
+    #[tokio::test]
+    async fn duplicate_prunes_redundant_edge() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut plumtree = started(tx).await;
+        let value = Payload::from(1);
+
+        plumtree.on_new_message(Some(&String::from("n1")), &value).await;
+        plumtree.on_duplicate_message(&String::from("n2"), &value).await;
+
+        assert!(matches!(sent(&mut rx).as_slice(), [(dest, MessageBody::Prune {})] if dest == "n2"));
+        let guard = plumtree.state.lock().await;
+        assert!(guard.lazy_peers.contains("n2"));
+        assert!(guard.eager_peers.contains("n1"));
+    }
+
+    #[tokio::test]
+    async fn retry_from_first_sender_keeps_edge() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut plumtree = started(tx).await;
+        let value = Payload::from(1);
+
+        plumtree.on_new_message(Some(&String::from("n1")), &value).await;
+        plumtree.on_duplicate_message(&String::from("n1"), &value).await;
+
+        assert!(sent(&mut rx).is_empty());
+        assert!(plumtree.state.lock().await.eager_peers.contains("n1"));
+    }
+
+    #[tokio::test]
+    async fn pruned_peer_is_grafted_back_and_eager_again() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut plumtree = started(tx).await;
+        plumtree.on_prune(&String::from("n3")).await;
+        assert!(plumtree.state.lock().await.lazy_peers.contains("n3"));
+
+        plumtree.on_graft(&String::from("n3")).await;
+        assert!(plumtree.state.lock().await.eager_peers.contains("n3"));
+        assert!(sent(&mut rx).is_empty());
+    }
+
+    #[tokio::test]
+    async fn missing_value_is_grafted_from_announcers_in_turn() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut plumtree = started(tx).await;
+        plumtree.set_neighbours(vec![String::from("n1")]).await;
+        let value = Payload::from(7);
+
+        plumtree.on_ihave(&String::from("n2"), vec![value.clone()]).await;
+        plumtree.on_ihave(&String::from("n3"), vec![value.clone()]).await;
+
+        tokio::time::sleep(MISSING_TIMEOUT + MISSING_TIMEOUT / 2).await;
+        let grafts = sent(&mut rx);
+        assert!(matches!(grafts.as_slice(), [(dest, MessageBody::Graft {messages})] if dest == "n2" && messages == &vec![value.clone()]));
+        assert!(plumtree.state.lock().await.eager_peers.contains("n2"));
+
+        //still missing, so the next announcer is asked
+        tokio::time::sleep(MISSING_TIMEOUT).await;
+        let grafts = sent(&mut rx);
+        assert!(matches!(grafts.as_slice(), [(dest, MessageBody::Graft {..})] if dest == "n3"));
+
+        //once it arrives nobody else is asked
+        plumtree.on_new_message(Some(&String::from("n3")), &value).await;
+        tokio::time::sleep(MISSING_TIMEOUT * 2).await;
+        assert!(!sent(&mut rx).iter().any(|(_, body)| matches!(body, MessageBody::Graft {..})));
+    }
+}
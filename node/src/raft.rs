@@ -0,0 +1,910 @@
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::RandomState;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::hash::BuildHasher;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+use tokio::sync::{oneshot, Mutex};
+use tokio::time::Instant;
+use crate::message::MessageBody;
+use crate::raft_storage::{RaftSnapshot, RaftStorage};
+use crate::rpc::Rpc;
+
+// how often a leader reaches out to every follower, also when it has nothing new for them
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(50);
+// a follower not hearing from a leader for a random time between these starts an election
+const ELECTION_TIMEOUT_MIN: Duration = Duration::from_millis(300);
+const ELECTION_TIMEOUT_MAX: Duration = Duration::from_millis(600);
+// how often timers are checked
+const TICK: Duration = Duration::from_millis(10);
+// shorter than an election timeout, so a lost reply doesn't hold a follower back for a whole term
+const RAFT_RPC_TIMEOUT: Duration = Duration::from_millis(200);
+const MAX_ENTRIES_PER_APPEND: usize = 128;
+// below the rpc timeout, so a follower relaying a request gets the leader's answer
+const PROPOSE_TIMEOUT: Duration = Duration::from_millis(800);
+// applied entries after which a node snapshots its state machine and drops them from its log
+pub const DEFAULT_SNAPSHOT_EVERY: u64 = 1000;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LogEntry {
+    pub term: u64,
+    // none for the entry a leader starts its term with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<serde_json::Value>
+}
+
+// what raft keeps consistent, every node applies the same commands in the same order
+pub trait StateMachine: Send + 'static {
+    type Command: Serialize + DeserializeOwned + Send;
+    type Output: Send + 'static;
+
+    fn apply(&mut self, command: Self::Command) -> Self::Output;
+
+    // everything applied so far, `restore` must bring any state machine back to it
+    fn snapshot(&self) -> serde_json::Value;
+
+    // a snapshot it can't make sense of leaves the state machine as it was
+    fn restore(&mut self, snapshot: serde_json::Value) -> serde_json::Result<()>;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RaftError {
+    // `leader` is the one this node last heard from, if any
+    NotLeader {leader: Option<String>},
+    // another leader's entry took its place in the log, it is never applied
+    Dropped,
+    // not applied in time, it still may be
+    Timeout
+}
+
+impl Display for RaftError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RaftError::NotLeader {leader: Some(leader)} => write!(f, "not the leader, {leader} is"),
+            RaftError::NotLeader {leader: None} => write!(f, "not the leader, no leader known"),
+            RaftError::Dropped => write!(f, "entry was replaced by another leader"),
+            RaftError::Timeout => write!(f, "entry was not applied in time")
+        }
+    }
+}
+
+impl Error for RaftError {}
+
+impl RaftError {
+    // proposals that never made it into the log are refused for sure, timed out ones may still be applied
+    pub fn code(&self) -> u32 {
+        match self {
+            RaftError::NotLeader {..} | RaftError::Dropped => 11,
+            RaftError::Timeout => 13
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Role {
+    Follower,
+    Candidate,
+    Leader
+}
+
+// a proposal waiting for its entry to be applied
+struct Waiter<O> {
+    term: u64,
+    tx: oneshot::Sender<Result<O, RaftError>>
+}
+
+// somewhere between the election timeouts, so nodes rarely start elections together
+fn election_timeout() -> Duration {
+    let spread = (ELECTION_TIMEOUT_MAX - ELECTION_TIMEOUT_MIN).as_millis() as u64;
+    let jitter = RandomState::new().hash_one(Instant::now()) % spread;
+    ELECTION_TIMEOUT_MIN + Duration::from_millis(jitter)
+}
+
+struct RaftState<S: StateMachine> {
+    node_id: String,
+    peers: Vec<String>,
+    role: Role,
+    leader: Option<String>,
+    storage: RaftStorage,
+    commit_index: u64,
+    last_applied: u64,
+    election_deadline: Instant,
+    last_heartbeat: Instant,
+    votes: HashSet<String>,
+    // leader only: next entry to send and highest entry known replicated, per peer
+    next_index: HashMap<String, u64>,
+    match_index: HashMap<String, u64>,
+    // peers with an append entries request on its way
+    replicating: HashSet<String>,
+    // peer -> when it last answered, a leader not hearing from a majority steps down
+    last_ack: HashMap<String, Instant>,
+    // log index -> proposal waiting for it
+    waiters: HashMap<u64, Waiter<S::Output>>,
+    state_machine: S,
+    snapshot_every: u64
+}
+
+impl<S: StateMachine> RaftState<S> {
+    fn term(&self) -> u64 {
+        self.storage.term()
+    }
+
+    fn last_log_term(&self) -> u64 {
+        self.storage.term_at(self.storage.last_index()).unwrap_or(0)
+    }
+
+    fn majority(&self) -> usize {
+        let cluster = self.peers.len() + 1;
+        cluster / 2 + 1
+    }
+
+    fn reset_election_deadline(&mut self) {
+        self.election_deadline = Instant::now() + election_timeout();
+    }
+
+    fn become_follower(&mut self, term: u64, leader: Option<String>) {
+        if term > self.term() {
+            self.storage.set_hard_state(term, None).expect("error occur while persisting raft state");
+        }
+        if self.role != Role::Follower {
+            self.role = Role::Follower;
+            self.reset_election_deadline();
+        }
+        self.leader = leader;
+        self.replicating.clear();
+    }
+
+    // returns what vote requests carry: term, last log index & term
+    fn start_election(&mut self) -> (u64, u64, u64) {
+        let term = self.term() + 1;
+        self.storage.set_hard_state(term, Some(self.node_id.clone())).expect("error occur while persisting raft state");
+        self.role = Role::Candidate;
+        self.leader = None;
+        self.votes = HashSet::from([self.node_id.clone()]);
+        self.reset_election_deadline();
+
+        //a single node is its own majority
+        if self.votes.len() >= self.majority() {
+            self.become_leader();
+        }
+        (term, self.storage.last_index(), self.last_log_term())
+    }
+
+    fn become_leader(&mut self) {
+        self.role = Role::Leader;
+        self.leader = Some(self.node_id.clone());
+        let now = Instant::now();
+        let next = self.storage.last_index() + 1;
+        for peer in &self.peers {
+            self.next_index.insert(peer.clone(), next);
+            self.match_index.insert(peer.clone(), 0);
+            self.last_ack.insert(peer.clone(), now);
+        }
+
+        //entries of earlier terms only commit together with one of the leader's own
+        let term = self.term();
+        self.storage.append(vec![LogEntry {term, command: None}]).expect("error occur while persisting raft log");
+        self.advance_commit();
+    }
+
+    fn has_quorum(&self, now: Instant) -> bool {
+        let heard = self.peers.iter()
+            .filter(|peer| self.last_ack.get(*peer).is_some_and(|at| now.duration_since(*at) < ELECTION_TIMEOUT_MAX))
+            .count();
+        heard + 1 >= self.majority()
+    }
+
+    // marks the peers nobody replicates to right now as taken and returns them
+    fn claim_idle_peers(&mut self) -> Vec<String> {
+        let idle = self.peers.iter().filter(|peer| !self.replicating.contains(*peer)).cloned().collect::<Vec<String>>();
+        self.replicating.extend(idle.iter().cloned());
+        idle
+    }
+
+    // commits the newest entry of the current term a majority holds, and everything before it
+    fn advance_commit(&mut self) {
+        let term = self.term();
+        let mut index = self.storage.last_index();
+        while index > self.commit_index {
+            match self.storage.term_at(index) {
+                Some(entry_term) if entry_term == term => {
+                    let replicas = 1 + self.match_index.values().filter(|matched| **matched >= index).count();
+                    if replicas >= self.majority() {
+                        self.commit_index = index;
+                        break
+                    }
+                },
+                _ => break
+            }
+            index -= 1;
+        }
+        self.apply_committed();
+    }
+
+    fn apply_committed(&mut self) {
+        while self.last_applied < self.commit_index {
+            self.last_applied += 1;
+            let entry = self.storage.entry(self.last_applied).cloned().expect("committed entries are in the log");
+            let output = entry.command.map(|command| {
+                let command = serde_json::from_value(command).expect("error occur while decoding a raft command");
+                self.state_machine.apply(command)
+            });
+
+            if let Some(waiter) = self.waiters.remove(&self.last_applied) {
+                let result = match output {
+                    Some(output) if waiter.term == entry.term => Ok(output),
+                    _ => Err(RaftError::Dropped)
+                };
+                let _ = waiter.tx.send(result);
+            }
+        }
+
+        if self.last_applied - self.storage.snapshot_index() >= self.snapshot_every {
+            let snapshot = RaftSnapshot {
+                last_included_index: self.last_applied,
+                last_included_term: self.storage.term_at(self.last_applied).expect("applied entries are in the log"),
+                state: self.state_machine.snapshot()
+            };
+            self.storage.save_snapshot(snapshot).expect("error occur while persisting raft snapshot");
+        }
+    }
+
+    fn vote(&mut self, term: u64, candidate: String, last_log_index: u64, last_log_term: u64) -> bool {
+        if term > self.term() {
+            self.become_follower(term, None);
+        }
+        if term < self.term() {
+            return false
+        }
+
+        let free = self.storage.voted_for().is_none_or(|voted| voted.eq(&candidate));
+        //only a candidate holding every committed entry can win
+        let up_to_date = (last_log_term, last_log_index) >= (self.last_log_term(), self.storage.last_index());
+        if !free || !up_to_date {
+            return false
+        }
+
+        self.storage.set_hard_state(term, Some(candidate)).expect("error occur while persisting raft state");
+        self.reset_election_deadline();
+        true
+    }
+
+    // returns whether the entries were taken and the last index known to match the leader's log
+    // on a mismatch that index is a hint where the leader should try again
+    fn append_entries(&mut self, term: u64, leader: String, prev_log_index: u64, prev_log_term: u64, entries: Vec<LogEntry>, leader_commit: u64) -> (bool, u64) {
+        if term < self.term() {
+            return (false, 0)
+        }
+        self.become_follower(term, Some(leader));
+        self.reset_election_deadline();
+
+        //entries the snapshot covers are committed, so they match the leader's, only the ones after it are checked
+        let snapshot_index = self.storage.snapshot_index();
+        let (prev_log_index, prev_log_term, entries) = match prev_log_index < snapshot_index {
+            true => {
+                let covered = (snapshot_index - prev_log_index) as usize;
+                if entries.len() <= covered {
+                    return (true, snapshot_index)
+                }
+                let prev_log_term = self.storage.term_at(snapshot_index).unwrap_or(0);
+                (snapshot_index, prev_log_term, entries.into_iter().skip(covered).collect())
+            },
+            false => (prev_log_index, prev_log_term, entries)
+        };
+
+        let last_index = self.storage.last_index();
+        if prev_log_index > last_index || self.storage.term_at(prev_log_index) != Some(prev_log_term) {
+            return (false, last_index.min(prev_log_index.saturating_sub(1)))
+        }
+
+        //entries already held are skipped, the first conflicting one cuts the log
+        let mut index = prev_log_index;
+        let mut new_entries = Vec::new();
+        for entry in entries {
+            index += 1;
+            if new_entries.is_empty() {
+                match self.storage.term_at(index) {
+                    Some(held) if held == entry.term => continue,
+                    Some(_) => self.storage.truncate_from(index).expect("error occur while persisting raft log"),
+                    None => {}
+                }
+            }
+            new_entries.push(entry);
+        }
+        if !new_entries.is_empty() {
+            self.storage.append(new_entries).expect("error occur while persisting raft log");
+        }
+
+        self.commit_index = self.commit_index.max(leader_commit.min(index));
+        self.apply_committed();
+        (true, index)
+    }
+
+    // replaces the state machine and the log it covers with the leader's snapshot
+    // one this node already has everything of is ignored, one its state machine can't restore is refused
+    fn install_snapshot(&mut self, term: u64, leader: String, snapshot: RaftSnapshot) -> serde_json::Result<()> {
+        if term < self.term() {
+            return Ok(())
+        }
+        self.become_follower(term, Some(leader));
+        self.reset_election_deadline();
+
+        let index = snapshot.last_included_index;
+        if index <= self.commit_index {
+            return Ok(())
+        }
+
+        self.state_machine.restore(snapshot.state.clone())?;
+        self.storage.save_snapshot(snapshot).expect("error occur while persisting raft snapshot");
+        self.commit_index = index;
+        self.last_applied = index;
+        //proposals of this node's own term as leader may or may not be in the snapshot, their waiters give up
+        self.waiters.retain(|waiting, _| *waiting > index);
+        Ok(())
+    }
+}
+
+// raft consensus over the node's peers
+// the leader orders proposals in a replicated log, entries a majority holds are applied to the state machine on every node
+pub(crate) struct Raft<S: StateMachine> {
+    state: Arc<Mutex<RaftState<S>>>,
+    rpc: Rpc
+}
+
+impl<S: StateMachine> Clone for Raft<S> {
+    fn clone(&self) -> Self {
+        Raft {
+            state: self.state.clone(),
+            rpc: self.rpc.clone()
+        }
+    }
+}
+
+impl<S: StateMachine> Raft<S> {
+
+    // a node restarting from a snapshot starts with its state machine restored from it
+    pub fn new(node_id: String, node_ids: Vec<String>, storage: RaftStorage, mut state_machine: S, snapshot_every: u64, rpc: Rpc) -> io::Result<Self> {
+        let now = Instant::now();
+        let peers = node_ids.into_iter().filter(|node| node.ne(&node_id)).collect();
+        let snapshot_index = storage.snapshot_index();
+        if let Some(snapshot) = storage.snapshot() {
+            state_machine.restore(snapshot.state.clone())?;
+        }
+        Ok(Raft {
+            state: Arc::new(Mutex::new(RaftState {
+                node_id,
+                peers,
+                role: Role::Follower,
+                leader: None,
+                storage,
+                commit_index: snapshot_index,
+                last_applied: snapshot_index,
+                election_deadline: now + election_timeout(),
+                last_heartbeat: now,
+                votes: HashSet::new(),
+                next_index: HashMap::new(),
+                match_index: HashMap::new(),
+                replicating: HashSet::new(),
+                last_ack: HashMap::new(),
+                waiters: HashMap::new(),
+                state_machine,
+                snapshot_every
+            })),
+            rpc
+        })
+    }
+
+    // drives elections and heartbeats
+    pub fn start(&self) {
+        let raft = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(TICK).await;
+                raft.tick().await;
+            }
+        });
+    }
+
+    async fn tick(&self) {
+        let mut state = self.state.lock().await;
+        let now = Instant::now();
+        match state.role {
+            Role::Leader => {
+                //a leader cut off from the majority can't commit anything, clients are better off elsewhere
+                if !state.has_quorum(now) {
+                    let term = state.term();
+                    state.become_follower(term, None);
+                    return
+                }
+                if now.duration_since(state.last_heartbeat) >= HEARTBEAT_INTERVAL {
+                    state.last_heartbeat = now;
+                    let peers = state.claim_idle_peers();
+                    drop(state);
+                    self.spawn_replication(peers);
+                }
+            },
+            Role::Follower | Role::Candidate if now >= state.election_deadline => {
+                let (term, last_log_index, last_log_term) = state.start_election();
+                let peers = state.peers.clone();
+                drop(state);
+                for peer in peers {
+                    let raft = self.clone();
+                    tokio::spawn(async move {
+                        raft.request_vote(peer, term, last_log_index, last_log_term).await
+                    });
+                }
+            },
+            _ => {}
+        }
+    }
+
+    async fn request_vote(&self, peer: String, term: u64, last_log_index: u64, last_log_term: u64) {
+        let node_id = self.state.lock().await.node_id.clone();
+        let request = |msg_id| MessageBody::RequestVote {msg_id, term, candidate_id: node_id.clone(), last_log_index, last_log_term};
+        let Ok(MessageBody::RequestVoteOk {term: reply_term, vote_granted, ..}) = self.rpc.call_with_timeout(&peer, RAFT_RPC_TIMEOUT, request).await else {
+            return
+        };
+
+        let mut state = self.state.lock().await;
+        if reply_term > state.term() {
+            state.become_follower(reply_term, None);
+            return
+        }
+        if !vote_granted || state.role != Role::Candidate || state.term() != term {
+            return
+        }
+
+        state.votes.insert(peer);
+        if state.votes.len() >= state.majority() {
+            state.become_leader();
+            state.last_heartbeat = Instant::now();
+            let peers = state.claim_idle_peers();
+            drop(state);
+            self.spawn_replication(peers);
+        }
+    }
+
+    fn spawn_replication(&self, peers: Vec<String>) {
+        for peer in peers {
+            let raft = self.clone();
+            tokio::spawn(async move {
+                raft.replicate(peer).await
+            });
+        }
+    }
+
+    // sends the peer what it misses until it is caught up, the peer must have been claimed first
+    async fn replicate(&self, peer: String) {
+        loop {
+            let mut state = self.state.lock().await;
+            if state.role != Role::Leader {
+                state.replicating.remove(&peer);
+                return
+            }
+
+            let term = state.term();
+            let leader_id = state.node_id.clone();
+            let next = state.next_index[&peer];
+            //the entries the peer needs are gone from the log, it gets the snapshot instead
+            if next <= state.storage.snapshot_index() {
+                let snapshot = state.storage.snapshot().cloned().expect("a compacted log has a snapshot");
+                drop(state);
+                if !self.send_snapshot(&peer, term, leader_id, snapshot).await {
+                    return
+                }
+                continue
+            }
+            let prev_log_index = next - 1;
+            let prev_log_term = state.storage.term_at(prev_log_index).unwrap_or(0);
+            let entries = state.storage.entries_from(next, MAX_ENTRIES_PER_APPEND);
+            let leader_commit = state.commit_index;
+            drop(state);
+
+            let request = |msg_id| MessageBody::AppendEntries {
+                msg_id,
+                term,
+                leader_id: leader_id.clone(),
+                prev_log_index,
+                prev_log_term,
+                entries: entries.clone(),
+                leader_commit
+            };
+            let reply = self.rpc.call_with_timeout(&peer, RAFT_RPC_TIMEOUT, request).await;
+
+            let mut state = self.state.lock().await;
+            //an unanswered peer is retried with the next heartbeat
+            let Ok(MessageBody::AppendEntriesOk {term: reply_term, success, match_index, ..}) = reply else {
+                state.replicating.remove(&peer);
+                return
+            };
+            if reply_term > state.term() {
+                state.become_follower(reply_term, None);
+                return
+            }
+            if state.role != Role::Leader || state.term() != term {
+                state.replicating.remove(&peer);
+                return
+            }
+
+            state.last_ack.insert(peer.clone(), Instant::now());
+            if success {
+                let matched = state.match_index[&peer].max(match_index);
+                state.match_index.insert(peer.clone(), matched);
+                state.next_index.insert(peer.clone(), matched + 1);
+                state.advance_commit();
+            } else {
+                state.next_index.insert(peer.clone(), (match_index + 1).min(prev_log_index).max(1));
+            }
+
+            if state.next_index[&peer] > state.storage.last_index() {
+                state.replicating.remove(&peer);
+                return
+            }
+        }
+    }
+
+    // returns whether to go on replicating to the peer
+    async fn send_snapshot(&self, peer: &String, term: u64, leader_id: String, snapshot: RaftSnapshot) -> bool {
+        let last_included_index = snapshot.last_included_index;
+        let request = |msg_id| MessageBody::InstallSnapshot {
+            msg_id,
+            term,
+            leader_id: leader_id.clone(),
+            last_included_index,
+            last_included_term: snapshot.last_included_term,
+            data: snapshot.state.clone()
+        };
+        let reply = self.rpc.call_with_timeout(peer, RAFT_RPC_TIMEOUT, request).await;
+
+        let mut state = self.state.lock().await;
+        let Ok(MessageBody::InstallSnapshotOk {term: reply_term, ..}) = reply else {
+            state.replicating.remove(peer);
+            return false
+        };
+        if reply_term > state.term() {
+            state.become_follower(reply_term, None);
+            return false
+        }
+        if state.role != Role::Leader || state.term() != term {
+            state.replicating.remove(peer);
+            return false
+        }
+
+        state.last_ack.insert(peer.clone(), Instant::now());
+        let matched = state.match_index[peer].max(last_included_index);
+        state.match_index.insert(peer.clone(), matched);
+        state.next_index.insert(peer.clone(), matched + 1);
+        state.advance_commit();
+        true
+    }
+
+    // appends the command to the log and waits until it is applied, only the leader takes proposals
+    pub async fn propose(&self, command: S::Command) -> Result<S::Output, RaftError> {
+        let command = serde_json::to_value(command).expect("error occur while encoding a raft command");
+
+        let mut state = self.state.lock().await;
+        if state.role != Role::Leader {
+            return Err(RaftError::NotLeader {leader: state.leader.clone()})
+        }
+        let term = state.term();
+        state.storage.append(vec![LogEntry {term, command: Some(command)}]).expect("error occur while persisting raft log");
+        let index = state.storage.last_index();
+        let (tx, rx) = oneshot::channel();
+        state.waiters.insert(index, Waiter {term, tx});
+        state.advance_commit();
+        let peers = state.claim_idle_peers();
+        drop(state);
+
+        self.spawn_replication(peers);
+
+        match tokio::time::timeout(PROPOSE_TIMEOUT, rx).await {
+            Ok(Ok(result)) => result,
+            _ => {
+                let mut state = self.state.lock().await;
+                if state.waiters.get(&index).is_some_and(|waiter| waiter.term == term) {
+                    state.waiters.remove(&index);
+                }
+                Err(RaftError::Timeout)
+            }
+        }
+    }
+
+    // answers another node's vote, append entries or install snapshot request
+    pub async fn on_message(&self, body: MessageBody) -> Option<MessageBody> {
+        let mut state = self.state.lock().await;
+        match body {
+            MessageBody::RequestVote {msg_id, term, candidate_id, last_log_index, last_log_term} => {
+                let vote_granted = state.vote(term, candidate_id, last_log_index, last_log_term);
+                Some(MessageBody::RequestVoteOk {in_reply_to: msg_id, term: state.term(), vote_granted})
+            },
+            MessageBody::AppendEntries {msg_id, term, leader_id, prev_log_index, prev_log_term, entries, leader_commit} => {
+                let (success, match_index) = state.append_entries(term, leader_id, prev_log_index, prev_log_term, entries, leader_commit);
+                Some(MessageBody::AppendEntriesOk {in_reply_to: msg_id, term: state.term(), success, match_index})
+            },
+            MessageBody::InstallSnapshot {msg_id, term, leader_id, last_included_index, last_included_term, data} => {
+                let snapshot = RaftSnapshot {last_included_index, last_included_term, state: data};
+                match state.install_snapshot(term, leader_id, snapshot) {
+                    Ok(()) => Some(MessageBody::InstallSnapshotOk {in_reply_to: msg_id, term: state.term()}),
+                    Err(e) => Some(MessageBody::Error {in_reply_to: msg_id, code: 12, text: format!("error occur while restoring raft snapshot: {e}")})
+                }
+            },
+            _ => None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tokio::sync::mpsc;
+    use crate::kv::KvError;
+    use crate::lin_kv::{KvCommand, KvStore};
+    use crate::message::{Message, MessageForm};
+    use super::*;
+
+    type Nodes = HashMap<String, (Raft<KvStore>, Rpc)>;
+
+    fn node_ids(size: usize) -> Vec<String> {
+        (0..size).map(|n| format!("n{n}")).collect()
+    }
+
+    fn write(key: u64, value: u64) -> Option<serde_json::Value> {
+        Some(serde_json::to_value(KvCommand::Write {key: json!(key), value: json!(value)}).unwrap())
+    }
+
+    fn entry(term: u64) -> LogEntry {
+        LogEntry {term, command: None}
+    }
+
+    // a node on its own, whatever it sends goes nowhere
+    fn single(node_ids: Vec<String>, storage: RaftStorage) -> Raft<KvStore> {
+        let (tx, _rx) = mpsc::unbounded_channel();
+        Raft::new(node_ids[0].clone(), node_ids, storage, KvStore::default(), DEFAULT_SNAPSHOT_EVERY, Rpc::new(tx)).unwrap()
+    }
+
+    // running nodes wired to each other, lin-kv requests are served like the node does
+    fn cluster(size: usize) -> Nodes {
+        let node_ids = node_ids(size);
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let nodes = node_ids.iter().map(|node_id| {
+            let mut rpc = Rpc::new(tx.clone());
+            rpc.set_node_id(node_id.clone());
+            let raft = Raft::new(node_id.clone(), node_ids.clone(), RaftStorage::new(), KvStore::default(), DEFAULT_SNAPSHOT_EVERY, rpc.clone()).unwrap();
+            raft.start();
+            (node_id.clone(), (raft, rpc))
+        }).collect::<Nodes>();
+
+        let routes = nodes.clone();
+        tokio::spawn(async move {
+            while let Some(MessageForm::NodeMessage(msg)) = rx.recv().await {
+                let (raft, rpc) = routes[&msg.dest].clone();
+                let sender = routes[&msg.src].1.clone();
+                tokio::spawn(async move {
+                    let Some(msg) = rpc.resolve(msg).await else { return };
+                    let body = match &msg.body {
+                        MessageBody::Read {msg_id, ..} | MessageBody::Write {msg_id, ..} | MessageBody::Cas {msg_id, ..} => {
+                            let command = KvCommand::from_request(msg.body.clone()).expect("lin-kv requests carry a key");
+                            Some(command.serve(&raft, &rpc, *msg_id).await)
+                        },
+                        _ => raft.on_message(msg.body.clone()).await
+                    };
+                    if let Some(body) = body {
+                        sender.resolve(Message {src: msg.dest, dest: msg.src, body}).await;
+                    }
+                });
+            }
+        });
+        nodes
+    }
+
+    async fn leader(nodes: &Nodes) -> String {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            for (node_id, (raft, _)) in nodes {
+                if raft.state.lock().await.role == Role::Leader {
+                    return node_id.clone()
+                }
+            }
+            assert!(Instant::now() < deadline, "no leader got elected");
+            tokio::time::sleep(TICK).await;
+        }
+    }
+
+    // waits until every node follows `leader`
+    async fn followed(nodes: &Nodes, leader: &String) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        for (raft, _) in nodes.values() {
+            while raft.state.lock().await.leader.as_ref() != Some(leader) {
+                assert!(Instant::now() < deadline, "{leader} is not followed");
+                tokio::time::sleep(TICK).await;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn cluster_elects_a_leader_everyone_follows() {
+        let nodes = cluster(3);
+        let leader = leader(&nodes).await;
+        followed(&nodes, &leader).await;
+
+        let term = nodes[&leader].0.state.lock().await.term();
+        for (node_id, (raft, _)) in &nodes {
+            let state = raft.state.lock().await;
+            assert_eq!(state.term(), term);
+            assert_eq!(state.role == Role::Leader, *node_id == leader);
+        }
+    }
+
+    #[tokio::test]
+    async fn vote_goes_to_one_up_to_date_candidate_per_term() {
+        let mut storage = RaftStorage::new();
+        storage.append(vec![entry(1), entry(2)]).unwrap();
+        let raft = single(node_ids(3), storage);
+        let mut state = raft.state.lock().await;
+
+        //a log ending in an older term is behind, however long it is
+        assert!(!state.vote(3, String::from("n1"), 5, 1));
+        assert!(state.vote(3, String::from("n2"), 2, 2));
+        assert!(state.vote(3, String::from("n2"), 2, 2));
+        assert!(!state.vote(3, String::from("n1"), 3, 2));
+        assert!(!state.vote(2, String::from("n1"), 3, 2));
+        assert_eq!(state.storage.voted_for(), Some(&String::from("n2")));
+
+        //a new term frees the vote
+        assert!(state.vote(4, String::from("n1"), 3, 2));
+    }
+
+    #[tokio::test]
+    async fn candidate_with_majority_leads_its_term() {
+        let raft = single(node_ids(3), RaftStorage::new());
+        let mut state = raft.state.lock().await;
+
+        assert_eq!(state.start_election(), (1, 0, 0));
+        assert_eq!(state.role, Role::Candidate);
+        assert_eq!(state.storage.voted_for(), Some(&String::from("n0")));
+
+        state.become_leader();
+        assert_eq!(state.leader, Some(String::from("n0")));
+        //the term starts with an entry of its own
+        assert_eq!(state.storage.last_index(), 1);
+        assert_eq!(state.storage.term_at(1), Some(1));
+
+        //a higher term from anyone ends it
+        assert!(state.vote(2, String::from("n1"), 1, 1));
+        assert_eq!(state.role, Role::Follower);
+    }
+
+    #[tokio::test]
+    async fn follower_repairs_a_conflicting_log() {
+        let mut storage = RaftStorage::new();
+        storage.set_hard_state(2, None).unwrap();
+        storage.append(vec![entry(1), entry(1), entry(2), entry(2)]).unwrap();
+        let raft = single(node_ids(3), storage);
+        let mut state = raft.state.lock().await;
+        let leader = String::from("n1");
+
+        //a previous entry it lacks or holds from another term is refused with where to try again
+        assert_eq!(state.append_entries(3, leader.clone(), 6, 3, vec![entry(3)], 0), (false, 4));
+        assert_eq!(state.append_entries(3, leader.clone(), 4, 3, vec![entry(3)], 0), (false, 3));
+        assert_eq!(state.storage.last_index(), 4);
+
+        //the first entry of another term cuts off the rest of the log
+        assert_eq!(state.append_entries(3, leader.clone(), 2, 1, vec![entry(3), entry(3)], 0), (true, 4));
+        assert_eq!(state.storage.term_at(3), Some(3));
+        assert_eq!(state.storage.term_at(4), Some(3));
+
+        //an older request arriving late takes nothing away
+        assert_eq!(state.append_entries(3, leader.clone(), 2, 1, vec![entry(3)], 0), (true, 3));
+        assert_eq!(state.storage.last_index(), 4);
+
+        //a stale leader is refused and learns nothing
+        assert_eq!(state.append_entries(2, String::from("n2"), 4, 3, vec![entry(2)], 0), (false, 0));
+        assert_eq!(state.leader, Some(leader));
+    }
+
+    #[tokio::test]
+    async fn leader_commits_what_a_majority_holds_from_its_own_term() {
+        let mut storage = RaftStorage::new();
+        storage.set_hard_state(1, None).unwrap();
+        storage.append(vec![LogEntry {term: 1, command: write(1, 10)}]).unwrap();
+        let raft = single(node_ids(3), storage);
+        let mut state = raft.state.lock().await;
+        state.start_election();
+        state.become_leader();
+        assert_eq!(state.commit_index, 0);
+
+        //the earlier term's entry is not committed on its own
+        state.match_index.insert(String::from("n1"), 1);
+        state.advance_commit();
+        assert_eq!(state.commit_index, 0);
+
+        state.match_index.insert(String::from("n2"), 2);
+        state.advance_commit();
+        assert_eq!(state.commit_index, 2);
+        assert_eq!(state.last_applied, 2);
+        assert_eq!(state.state_machine.apply(KvCommand::Read {key: json!(1)}), Ok(json!(10)));
+
+        //a follower learns the commit index from the leader
+        let follower = single(vec![String::from("n1"), String::from("n0")], RaftStorage::new());
+        let mut follower = follower.state.lock().await;
+        let entries = state.storage.entries_from(1, MAX_ENTRIES_PER_APPEND);
+        assert_eq!(follower.append_entries(2, String::from("n0"), 0, 0, entries, state.commit_index), (true, 2));
+        assert_eq!(follower.commit_index, 2);
+        assert_eq!(follower.state_machine.apply(KvCommand::Read {key: json!(1)}), Ok(json!(10)));
+    }
+
+    #[tokio::test]
+    async fn lagging_follower_installs_the_leaders_snapshot_and_keeps_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = RaftStorage::open(dir.path()).unwrap();
+        storage.set_hard_state(1, None).unwrap();
+        storage.append(vec![LogEntry {term: 1, command: write(1, 10)}, LogEntry {term: 1, command: write(2, 20)}]).unwrap();
+        let raft = single(node_ids(3), storage);
+        raft.on_message(MessageBody::AppendEntries {msg_id: 1, term: 1, leader_id: String::from("n1"), prev_log_index: 2, prev_log_term: 1, entries: Vec::new(), leader_commit: 1}).await;
+
+        //the leader compacted past everything the follower holds, its uncommitted entry included
+        let data = json!({"1": 11, "3": 30});
+        let install = MessageBody::InstallSnapshot {msg_id: 2, term: 2, leader_id: String::from("n1"), last_included_index: 7, last_included_term: 2, data};
+        assert!(matches!(raft.on_message(install).await, Some(MessageBody::InstallSnapshotOk {in_reply_to: 2, term: 2})));
+        {
+            let mut state = raft.state.lock().await;
+            assert_eq!((state.commit_index, state.last_applied, state.storage.last_index()), (7, 7, 7));
+            assert_eq!(state.state_machine.apply(KvCommand::Read {key: json!(2)}), Err(KvError::KeyDoesNotExist));
+            assert_eq!(state.state_machine.apply(KvCommand::Read {key: json!(3)}), Ok(json!(30)));
+        }
+
+        //the leader goes on from right after the snapshot
+        let entries = vec![LogEntry {term: 2, command: write(1, 12)}];
+        let append = MessageBody::AppendEntries {msg_id: 3, term: 2, leader_id: String::from("n1"), prev_log_index: 7, prev_log_term: 2, entries, leader_commit: 8};
+        assert!(matches!(raft.on_message(append).await, Some(MessageBody::AppendEntriesOk {in_reply_to: 3, term: 2, success: true, match_index: 8})));
+        drop(raft);
+
+        //a restart restores the snapshot and replays the rest once the leader commits it again
+        let raft = single(node_ids(3), RaftStorage::open(dir.path()).unwrap());
+        let mut state = raft.state.lock().await;
+        assert_eq!((state.commit_index, state.storage.snapshot_index(), state.storage.last_index()), (7, 7, 8));
+        assert_eq!(state.state_machine.apply(KvCommand::Read {key: json!(1)}), Ok(json!(11)));
+        state.append_entries(2, String::from("n1"), 8, 2, Vec::new(), 8);
+        assert_eq!(state.state_machine.apply(KvCommand::Read {key: json!(1)}), Ok(json!(12)));
+    }
+
+    #[tokio::test]
+    async fn snapshot_the_state_machine_cannot_restore_is_refused() {
+        let mut storage = RaftStorage::new();
+        storage.append(vec![LogEntry {term: 1, command: write(1, 10)}]).unwrap();
+        let raft = single(node_ids(3), storage);
+        raft.on_message(MessageBody::AppendEntries {msg_id: 1, term: 1, leader_id: String::from("n1"), prev_log_index: 1, prev_log_term: 1, entries: Vec::new(), leader_commit: 1}).await;
+
+        let install = MessageBody::InstallSnapshot {msg_id: 2, term: 1, leader_id: String::from("n1"), last_included_index: 5, last_included_term: 1, data: json!([1, 2])};
+        assert!(matches!(raft.on_message(install).await, Some(MessageBody::Error {in_reply_to: 2, code: 12, ..})));
+
+        let mut state = raft.state.lock().await;
+        assert_eq!(state.storage.snapshot_index(), 0);
+        assert_eq!(state.commit_index, 1);
+        assert_eq!(state.state_machine.apply(KvCommand::Read {key: json!(1)}), Ok(json!(10)));
+    }
+
+    #[tokio::test]
+    async fn follower_relays_requests_to_the_leader() {
+        let nodes = cluster(3);
+        let leader = leader(&nodes).await;
+        followed(&nodes, &leader).await;
+        let mut followers = nodes.iter().filter(|(node_id, _)| **node_id != leader).map(|(_, node)| node);
+        let (first, first_rpc) = followers.next().unwrap();
+        let (second, second_rpc) = followers.next().unwrap();
+
+        let write = KvCommand::Write {key: json!(1), value: json!(2)};
+        assert_eq!(first.propose(write.clone()).await.err(), Some(RaftError::NotLeader {leader: Some(leader.clone())}));
+        assert!(matches!(write.serve(first, first_rpc, 7).await, MessageBody::WriteOk {in_reply_to: 7}));
+
+        let read = KvCommand::Read {key: json!(1)};
+        assert!(matches!(read.serve(second, second_rpc, 8).await, MessageBody::ReadOk {in_reply_to: 8, value} if value == json!(2)));
+
+        let cas = KvCommand::Cas {key: json!(1), from: json!(3), to: json!(4), create_if_not_exists: false};
+        assert!(matches!(cas.serve(second, second_rpc, 9).await, MessageBody::Error {in_reply_to: 9, code: 22, ..}));
+    }
+}
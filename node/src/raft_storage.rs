@@ -0,0 +1,432 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use crate::raft::LogEntry;
+
+const STATE_FILE: &str = "state.json";
+const SNAPSHOT_FILE: &str = "snapshot.json";
+
+// what a node must remember across restarts besides its log
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct HardState {
+    term: u64,
+    voted_for: Option<String>
+}
+
+// state machine as of `last_included_index`, it stands for every entry up to there
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct RaftSnapshot {
+    pub last_included_index: u64,
+    pub last_included_term: u64,
+    pub state: serde_json::Value
+}
+
+// log files are named by the index of their first entry, one json entry per line
+fn log_path(dir: &Path, first_index: u64) -> PathBuf {
+    dir.join(format!("log-{first_index:020}.jsonl"))
+}
+
+fn log_first_index(file_name: &str) -> Option<u64> {
+    file_name.strip_prefix("log-")?.strip_suffix(".jsonl")?.parse().ok()
+}
+
+// a rename or a newly created file is only durable once its directory is synced
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+// writes `value` to a temporary file and swaps it in
+fn write_json<T: Serialize>(dir: &Path, name: &str, value: &T) -> io::Result<()> {
+    let path = dir.join(name);
+    let tmp_path = path.with_extension("tmp");
+    let mut file = File::create(&tmp_path)?;
+    serde_json::to_writer(&mut file, value)?;
+    file.sync_all()?;
+    fs::rename(tmp_path, path)?;
+    //an old state.json coming back could let the node vote twice in one term
+    sync_dir(dir)
+}
+
+// raft answers only after its writes are on disk, so every write is synced right away
+struct RaftDisk {
+    dir: PathBuf,
+    log: File,
+    first_index: u64
+}
+
+impl RaftDisk {
+    fn save_state(&self, state: &HardState) -> io::Result<()> {
+        write_json(&self.dir, STATE_FILE, state)
+    }
+
+    fn save_snapshot(&self, snapshot: &RaftSnapshot) -> io::Result<()> {
+        write_json(&self.dir, SNAPSHOT_FILE, snapshot)
+    }
+
+    fn append(&mut self, entries: &[LogEntry]) -> io::Result<()> {
+        let mut writer = BufWriter::new(&self.log);
+        for entry in entries {
+            serde_json::to_writer(&mut writer, entry)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()?;
+        drop(writer);
+        self.log.sync_data()
+    }
+
+    // entries only ever go away from either end, rare enough to write a whole new file
+    // the file it replaces stays until `remove_stale_logs`, so a crash in between finds one of them intact
+    fn write_log(&mut self, first_index: u64, entries: &[LogEntry]) -> io::Result<()> {
+        let path = log_path(&self.dir, first_index);
+        let tmp_path = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        for entry in entries {
+            serde_json::to_writer(&mut writer, entry)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(&tmp_path, &path)?;
+        sync_dir(&self.dir)?;
+        self.log = OpenOptions::new().append(true).open(&path)?;
+        self.first_index = first_index;
+        Ok(())
+    }
+
+    fn remove_stale_logs(&self) -> io::Result<()> {
+        for dir_entry in fs::read_dir(&self.dir)? {
+            let dir_entry = dir_entry?;
+            let file_name = dir_entry.file_name();
+            if log_first_index(&file_name.to_string_lossy()).is_some_and(|first_index| first_index != self.first_index) {
+                fs::remove_file(dir_entry.path())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// term, vote, snapshot and log of a raft node, kept in memory and also on disk when opened from a directory
+// the log holds the entries after the snapshot, index 0 stands for the empty log and has term 0
+pub(crate) struct RaftStorage {
+    state: HardState,
+    snapshot: Option<RaftSnapshot>,
+    entries: Vec<LogEntry>,
+    disk: Option<RaftDisk>
+}
+
+impl RaftStorage {
+
+    pub fn new() -> Self {
+        RaftStorage {
+            state: HardState::default(),
+            snapshot: None,
+            entries: Vec::new(),
+            disk: None
+        }
+    }
+
+    // a line cut short by a crash ends the log, it was never acknowledged
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+
+        let read_json = |name: &str| match fs::read(dir.join(name)) {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e)
+        };
+        let state = match read_json(STATE_FILE)? {
+            Some(bytes) => serde_json::from_slice(&bytes)?,
+            None => HardState::default()
+        };
+        let snapshot = match read_json(SNAPSHOT_FILE)? {
+            Some(bytes) => Some(serde_json::from_slice::<RaftSnapshot>(&bytes)?),
+            None => None
+        };
+        let snapshot_index = snapshot.as_ref().map(|snapshot| snapshot.last_included_index).unwrap_or(0);
+
+        //the newest log the snapshot leaves no gap before, a newer one belongs to a snapshot that never got saved
+        let mut first_index = None;
+        for dir_entry in fs::read_dir(dir)? {
+            let file_name = dir_entry?.file_name();
+            if let Some(index) = log_first_index(&file_name.to_string_lossy()) && index <= snapshot_index + 1 {
+                first_index = first_index.max(Some(index));
+            }
+        }
+
+        //a line is only whole once its newline is written, one without it or cut inside a character is torn too
+        let mut entries = Vec::new();
+        let mut torn = false;
+        if let Some(first_index) = first_index {
+            let bytes = fs::read(log_path(dir, first_index))?;
+            for line in bytes.split_inclusive(|byte| *byte == b'\n') {
+                match line.strip_suffix(b"\n").map(serde_json::from_slice::<LogEntry>) {
+                    Some(Ok(entry)) => entries.push(entry),
+                    _ => {
+                        torn = true;
+                        break
+                    }
+                }
+            }
+        }
+
+        //entries the snapshot covers are dropped
+        let covered = (snapshot_index + 1 - first_index.unwrap_or(snapshot_index + 1)) as usize;
+        entries.drain(..covered.min(entries.len()));
+
+        let path = log_path(dir, snapshot_index + 1);
+        let log = OpenOptions::new().create(true).append(true).open(&path)?;
+        sync_dir(dir)?;
+        let mut disk = RaftDisk {dir: dir.to_path_buf(), log, first_index: snapshot_index + 1};
+        if torn || first_index != Some(snapshot_index + 1) {
+            disk.write_log(snapshot_index + 1, &entries)?;
+        }
+        disk.remove_stale_logs()?;
+
+        Ok(RaftStorage {
+            state,
+            snapshot,
+            entries,
+            disk: Some(disk)
+        })
+    }
+
+    pub fn term(&self) -> u64 {
+        self.state.term
+    }
+
+    pub fn voted_for(&self) -> Option<&String> {
+        self.state.voted_for.as_ref()
+    }
+
+    pub fn set_hard_state(&mut self, term: u64, voted_for: Option<String>) -> io::Result<()> {
+        self.state = HardState {term, voted_for};
+        match &self.disk {
+            Some(disk) => disk.save_state(&self.state),
+            None => Ok(())
+        }
+    }
+
+    pub fn snapshot(&self) -> Option<&RaftSnapshot> {
+        self.snapshot.as_ref()
+    }
+
+    pub fn snapshot_index(&self) -> u64 {
+        self.snapshot.as_ref().map(|snapshot| snapshot.last_included_index).unwrap_or(0)
+    }
+
+    pub fn last_index(&self) -> u64 {
+        self.snapshot_index() + self.entries.len() as u64
+    }
+
+    // none for indexes past the log and the ones the snapshot covers, except its last one
+    pub fn term_at(&self, index: u64) -> Option<u64> {
+        match &self.snapshot {
+            Some(snapshot) if index == snapshot.last_included_index => Some(snapshot.last_included_term),
+            _ if index == 0 => Some(0),
+            _ => self.entry(index).map(|entry| entry.term)
+        }
+    }
+
+    pub fn entry(&self, index: u64) -> Option<&LogEntry> {
+        let position = index.checked_sub(self.snapshot_index() + 1)?;
+        self.entries.get(position as usize)
+    }
+
+    // at most `max` entries starting at `index`, which must be past the snapshot
+    pub fn entries_from(&self, index: u64, max: usize) -> Vec<LogEntry> {
+        let start = index.saturating_sub(self.snapshot_index() + 1) as usize;
+        self.entries.iter().skip(start).take(max).cloned().collect()
+    }
+
+    pub fn append(&mut self, entries: Vec<LogEntry>) -> io::Result<()> {
+        if let Some(disk) = &mut self.disk {
+            disk.append(&entries)?;
+        }
+        self.entries.extend(entries);
+        Ok(())
+    }
+
+    // drops the entry at `index` and every one after it, only entries past the snapshot can go
+    pub fn truncate_from(&mut self, index: u64) -> io::Result<()> {
+        let first_index = self.snapshot_index() + 1;
+        self.entries.truncate(index.saturating_sub(first_index) as usize);
+        match &mut self.disk {
+            Some(disk) => disk.write_log(first_index, &self.entries),
+            None => Ok(())
+        }
+    }
+
+    // replaces the entries up to the snapshot's last one with it
+    // entries after it stay if the log agrees with the snapshot, otherwise the whole log goes
+    pub fn save_snapshot(&mut self, snapshot: RaftSnapshot) -> io::Result<()> {
+        let index = snapshot.last_included_index;
+        if index <= self.snapshot_index() {
+            return Ok(())
+        }
+
+        match self.term_at(index) == Some(snapshot.last_included_term) {
+            true => {
+                self.entries.drain(..(index - self.snapshot_index()) as usize);
+            },
+            false => self.entries.clear()
+        }
+        if let Some(disk) = &mut self.disk {
+            disk.write_log(index + 1, &self.entries)?;
+            disk.save_snapshot(&snapshot)?;
+            disk.remove_stale_logs()?;
+        }
+        self.snapshot = Some(snapshot);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn entries(indexes: std::ops::RangeInclusive<u64>) -> Vec<LogEntry> {
+        indexes.map(|index| LogEntry {term: 1, command: Some(json!(index))}).collect()
+    }
+
+    fn snapshot(last_included_index: u64) -> RaftSnapshot {
+        RaftSnapshot {last_included_index, last_included_term: 1, state: json!({"applied": last_included_index})}
+    }
+
+    fn log_files(dir: &Path) -> Vec<String> {
+        let mut names = fs::read_dir(dir).unwrap()
+            .map(|dir_entry| dir_entry.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| log_first_index(name).is_some())
+            .collect::<Vec<String>>();
+        names.sort();
+        names
+    }
+
+    fn commands(storage: &RaftStorage) -> Vec<serde_json::Value> {
+        (storage.snapshot_index() + 1..=storage.last_index()).map(|index| storage.entry(index).unwrap().command.clone().unwrap()).collect()
+    }
+
+    #[test]
+    fn torn_last_line_is_dropped_and_rewritten() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = RaftStorage::open(dir.path()).unwrap();
+        storage.set_hard_state(1, Some(String::from("n1"))).unwrap();
+        storage.append(entries(1..=3)).unwrap();
+        drop(storage);
+        let mut log = OpenOptions::new().append(true).open(log_path(dir.path(), 1)).unwrap();
+        log.write_all(b"{\"term\":1,\"comm").unwrap();
+
+        let mut storage = RaftStorage::open(dir.path()).unwrap();
+        assert_eq!(storage.term(), 1);
+        assert_eq!(storage.voted_for(), Some(&String::from("n1")));
+        assert_eq!(commands(&storage), vec![json!(1), json!(2), json!(3)]);
+
+        //entries appended after the torn line must not end up behind it
+        storage.append(entries(4..=4)).unwrap();
+        drop(storage);
+        let storage = RaftStorage::open(dir.path()).unwrap();
+        assert_eq!(commands(&storage), vec![json!(1), json!(2), json!(3), json!(4)]);
+    }
+
+    #[test]
+    fn entry_missing_its_newline_is_dropped_before_appending() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = RaftStorage::open(dir.path()).unwrap();
+        storage.append(entries(1..=2)).unwrap();
+        drop(storage);
+        let mut log = OpenOptions::new().append(true).open(log_path(dir.path(), 1)).unwrap();
+        serde_json::to_writer(&mut log, &entries(3..=3)[0]).unwrap();
+
+        //appending onto the same line would make both entries unreadable on the next recovery
+        let mut storage = RaftStorage::open(dir.path()).unwrap();
+        assert_eq!(commands(&storage), vec![json!(1), json!(2)]);
+        storage.append(entries(3..=4)).unwrap();
+        drop(storage);
+        let storage = RaftStorage::open(dir.path()).unwrap();
+        assert_eq!(commands(&storage), vec![json!(1), json!(2), json!(3), json!(4)]);
+    }
+
+    #[test]
+    fn line_torn_inside_a_character_is_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = RaftStorage::open(dir.path()).unwrap();
+        storage.append(entries(1..=2)).unwrap();
+        drop(storage);
+        let mut log = OpenOptions::new().append(true).open(log_path(dir.path(), 1)).unwrap();
+        log.write_all("{\"term\":1,\"command\":\"é".as_bytes().split_last().unwrap().1).unwrap();
+
+        let mut storage = RaftStorage::open(dir.path()).unwrap();
+        assert_eq!(commands(&storage), vec![json!(1), json!(2)]);
+        storage.append(entries(3..=3)).unwrap();
+        drop(storage);
+        let storage = RaftStorage::open(dir.path()).unwrap();
+        assert_eq!(commands(&storage), vec![json!(1), json!(2), json!(3)]);
+    }
+
+    #[test]
+    fn stale_logs_next_to_a_newer_snapshot_are_ignored_and_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = RaftStorage::open(dir.path()).unwrap();
+        storage.append(entries(1..=5)).unwrap();
+        storage.save_snapshot(snapshot(3)).unwrap();
+        drop(storage);
+        //one left from before the snapshot, one written for a snapshot that never got saved
+        fs::copy(log_path(dir.path(), 4), log_path(dir.path(), 1)).unwrap();
+        fs::write(log_path(dir.path(), 9), "").unwrap();
+
+        let storage = RaftStorage::open(dir.path()).unwrap();
+        assert_eq!(storage.snapshot_index(), 3);
+        assert_eq!(storage.snapshot().unwrap().state, json!({"applied": 3}));
+        assert_eq!(commands(&storage), vec![json!(4), json!(5)]);
+        assert_eq!(log_files(dir.path()), vec![String::from("log-00000000000000000004.jsonl")]);
+    }
+
+    #[test]
+    fn snapshot_survives_a_crash_before_its_log_was_swapped_in() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = RaftStorage::open(dir.path()).unwrap();
+        storage.append(entries(1..=5)).unwrap();
+        drop(storage);
+        write_json(dir.path(), SNAPSHOT_FILE, &snapshot(3)).unwrap();
+
+        //the older log still holds what comes after the snapshot
+        let storage = RaftStorage::open(dir.path()).unwrap();
+        assert_eq!(storage.snapshot_index(), 3);
+        assert_eq!(commands(&storage), vec![json!(4), json!(5)]);
+        assert_eq!(log_files(dir.path()), vec![String::from("log-00000000000000000004.jsonl")]);
+    }
+
+    #[test]
+    fn truncating_after_a_snapshot_keeps_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = RaftStorage::open(dir.path()).unwrap();
+        storage.append(entries(1..=5)).unwrap();
+        storage.save_snapshot(snapshot(2)).unwrap();
+        storage.truncate_from(4).unwrap();
+        assert_eq!(storage.last_index(), 3);
+        storage.append(vec![LogEntry {term: 2, command: Some(json!("new"))}]).unwrap();
+        drop(storage);
+
+        let storage = RaftStorage::open(dir.path()).unwrap();
+        assert_eq!(storage.snapshot_index(), 2);
+        assert_eq!(storage.term_at(2), Some(1));
+        assert_eq!(commands(&storage), vec![json!(3), json!("new")]);
+        assert_eq!(storage.term_at(4), Some(2));
+        assert_eq!(storage.entry(5), None);
+    }
+
+    #[test]
+    fn snapshot_past_the_log_replaces_all_of_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = RaftStorage::open(dir.path()).unwrap();
+        storage.append(entries(1..=2)).unwrap();
+        storage.save_snapshot(snapshot(10)).unwrap();
+        assert_eq!(storage.last_index(), 10);
+        drop(storage);
+
+        let storage = RaftStorage::open(dir.path()).unwrap();
+        assert_eq!(storage.snapshot_index(), 10);
+        assert_eq!(storage.last_index(), 10);
+        assert_eq!(log_files(dir.path()), vec![String::from("log-00000000000000000011.jsonl")]);
+    }
+}
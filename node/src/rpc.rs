@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot, Mutex};
+use crate::message::{Message, MessageBody, MessageForm};
+
+// how long a request to another node waits for its reply
+pub const RPC_TIMEOUT: Duration = Duration::from_millis(1000);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RpcError {
+    Timeout,
+    // the peer answered with maelstrom's `error` body
+    Remote {code: u32, text: String}
+}
+
+impl Display for RpcError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RpcError::Timeout => write!(f, "rpc timed out"),
+            RpcError::Remote {code, text} => write!(f, "remote error {code}: {text}")
+        }
+    }
+}
+
+impl Error for RpcError {}
+
+// request/reply between nodes
+// it owns the msg_id sequence of everything the node sends on its own, so replies never get mixed up
+#[derive(Clone)]
+pub struct Rpc {
+    node_id: String,
+    out: mpsc::UnboundedSender<MessageForm>,
+    pending: Arc<Mutex<HashMap<u32, oneshot::Sender<MessageBody>>>>,
+    next_msg_id: Arc<AtomicU32>
+}
+
+impl Rpc {
+
+    pub fn new(out: mpsc::UnboundedSender<MessageForm>) -> Self {
+        Rpc {
+            node_id: String::new(),
+            out,
+            pending: Arc::new(Mutex::new(HashMap::new())),
+            next_msg_id: Arc::new(AtomicU32::new(1))
+        }
+    }
+
+    pub fn set_node_id(&mut self, node_id: String) {
+        self.node_id = node_id;
+    }
+
+    pub fn next_msg_id(&self) -> u32 {
+        self.next_msg_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    // hands a reply over to the waiting call and gives back everything that is not a reply
+    // a reply nobody waits for anymore came in after its call gave up, it is dropped
+    pub async fn resolve(&self, msg: Message<MessageBody>) -> Option<Message<MessageBody>> {
+        let Some(in_reply_to) = msg.in_reply_to() else { return Some(msg) };
+
+        if let Some(sender) = self.pending.lock().await.remove(&in_reply_to) {
+            let _ = sender.send(msg.body);
+        }
+        None
+    }
+
+    // waits for the reply to a request the caller sends and retries on its own
+    pub async fn expect_reply(&self, msg_id: u32) -> oneshot::Receiver<MessageBody> {
+        let (tx, rx) = oneshot::channel::<MessageBody>();
+        self.pending.lock().await.insert(msg_id, tx);
+        rx
+    }
+
+    // stops waiting for the reply to `msg_id`
+    pub async fn forget(&self, msg_id: u32) {
+        self.pending.lock().await.remove(&msg_id);
+    }
+
+    pub async fn call(&self, dest: &str, make_body: impl FnOnce(u32) -> MessageBody) -> Result<MessageBody, RpcError> {
+        self.call_with_timeout(dest, RPC_TIMEOUT, make_body).await
+    }
+
+    pub async fn call_with_timeout(&self, dest: &str, timeout: Duration, make_body: impl FnOnce(u32) -> MessageBody) -> Result<MessageBody, RpcError> {
+        let msg_id = self.next_msg_id();
+        let rx = self.expect_reply(msg_id).await;
+
+        let msg = Message {
+            src: String::from(&self.node_id),
+            dest: String::from(dest),
+            body: make_body(msg_id)
+        };
+        let _ = self.out.send(msg.into());
+
+        let reply = match tokio::time::timeout(timeout, rx).await {
+            Ok(Ok(reply)) => reply,
+            _ => {
+                self.forget(msg_id).await;
+                return Err(RpcError::Timeout)
+            }
+        };
+
+        match reply {
+            MessageBody::Error {code, text, ..} => Err(RpcError::Remote {code, text}),
+            reply => Ok(reply)
+        }
+    }
+
+    // keeps asking until `dest` answers, for messages that must get through once a partition heals
+    pub async fn call_until_answered(&self, dest: &str, make_body: impl Fn(u32) -> MessageBody) -> Result<MessageBody, RpcError> {
+        loop {
+            match self.call(dest, &make_body).await {
+                Err(RpcError::Timeout) => continue,
+                answer => return answer
+            }
+        }
+    }
+}
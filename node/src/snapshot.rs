@@ -0,0 +1,140 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use crate::message::Payload;
+
+// one line of a snapshot file
+// a key's records follow its `key` line in offset order, committed offsets may come anywhere
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum SnapshotLine {
+    // records cover earliest_offset..high_watermark without gaps unless the log was compacted
+    Key {
+        key: String,
+        earliest_offset: usize,
+        high_watermark: usize,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")] compacted: bool
+    },
+    Record {key: String, offset: usize, msg: Payload},
+    Committed {group: String, key: String, offset: usize}
+}
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    // `line` counts from 1
+    Corrupt {line: usize, reason: String},
+    // the snapshot can't be restored into the current state
+    Rejected(String)
+}
+
+impl Display for SnapshotError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnapshotError::Io(e) => write!(f, "snapshot io error: {e}"),
+            SnapshotError::Corrupt {line, reason} => write!(f, "corrupt snapshot at line {line}: {reason}"),
+            SnapshotError::Rejected(reason) => write!(f, "snapshot rejected: {reason}")
+        }
+    }
+}
+
+impl Error for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+    fn from(e: io::Error) -> Self {
+        SnapshotError::Io(e)
+    }
+}
+
+// a key's log as read from a snapshot
+pub(crate) struct SnapshotLog {
+    pub earliest_offset: usize,
+    pub high_watermark: usize,
+    pub records: Vec<(usize, Payload)>
+}
+
+pub(crate) struct Snapshot {
+    pub logs: HashMap<String, SnapshotLog>,
+    // group_id -> log_key & committed offset
+    pub committed: HashMap<String, HashMap<String, usize>>
+}
+
+// written to a temporary file and swapped in, so a crash never leaves half a snapshot behind
+pub(crate) fn write_snapshot(path: &Path, lines: impl Iterator<Item = SnapshotLine>) -> io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    for line in lines {
+        serde_json::to_writer(&mut writer, &line)?;
+        writer.write_all(b"\n")?;
+    }
+    writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    std::fs::rename(tmp_path, path)
+}
+
+pub(crate) fn read_snapshot(path: &Path) -> Result<Snapshot, SnapshotError> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut logs: HashMap<String, SnapshotLog> = HashMap::new();
+    let mut compacted = HashMap::new();
+    let mut committed: HashMap<String, HashMap<String, usize>> = HashMap::new();
+    let mut line_count = 0;
+
+    for (index, line) in reader.lines().enumerate() {
+        let line_no = index + 1;
+        line_count = line_no;
+        let corrupt = |reason: String| SnapshotError::Corrupt {line: line_no, reason};
+        let line = line?;
+        if line.trim().is_empty() {
+            continue
+        }
+
+        match serde_json::from_str::<SnapshotLine>(&line).map_err(|e| corrupt(e.to_string()))? {
+            SnapshotLine::Key {key, earliest_offset, high_watermark, compacted: is_compacted} => {
+                if logs.contains_key(&key) {
+                    return Err(corrupt(format!("key {key} appears twice")))
+                }
+                if earliest_offset > high_watermark {
+                    return Err(corrupt(format!("key {key} starts at {earliest_offset} past its high watermark {high_watermark}")))
+                }
+                compacted.insert(key.clone(), is_compacted);
+                logs.insert(key, SnapshotLog {earliest_offset, high_watermark, records: Vec::new()});
+            },
+            SnapshotLine::Record {key, offset, msg} => {
+                let Some(log) = logs.get_mut(&key) else {
+                    return Err(corrupt(format!("record of key {key} before its key line")))
+                };
+
+                //without compaction every offset is there, otherwise they only have to go up
+                let expected = log.records.last().map(|(last, _)| last + 1).unwrap_or(log.earliest_offset);
+                let in_order = match compacted[&key] {
+                    true => offset >= expected,
+                    false => offset == expected
+                };
+                if !in_order {
+                    return Err(corrupt(format!("key {key} expects offset {expected}, got {offset}")))
+                }
+                if offset >= log.high_watermark {
+                    return Err(corrupt(format!("offset {offset} of key {key} is past its high watermark {}", log.high_watermark)))
+                }
+                log.records.push((offset, msg));
+            },
+            SnapshotLine::Committed {group, key, offset} => {
+                committed.entry(group).or_default().insert(key, offset);
+            }
+        }
+    }
+
+    //retention and compaction never drop a key's newest record, so a log ending early was cut off
+    for (key, log) in &logs {
+        let end = log.records.last().map(|(last, _)| last + 1).unwrap_or(log.earliest_offset);
+        if end != log.high_watermark {
+            let reason = format!("snapshot ends with key {key} at offset {end}, its high watermark is {}", log.high_watermark);
+            return Err(SnapshotError::Corrupt {line: line_count, reason})
+        }
+    }
+
+    Ok(Snapshot {logs, committed})
+}
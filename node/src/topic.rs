@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+// how a send without an explicit partition picks one
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Partitioner {
+    // by the hash of the record key, so one record key always lands in the same partition
+    // sends without a record key go round-robin
+    #[default]
+    Hash,
+    RoundRobin
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TopicConfig {
+    pub partitions: usize,
+    pub partitioner: Partitioner
+}
+
+impl Default for TopicConfig {
+    fn default() -> Self {
+        TopicConfig {
+            partitions: 1,
+            partitioner: Partitioner::default()
+        }
+    }
+}
+
+// partition keys live under a prefix plain kafka keys may not use, so the two never share a log
+const PARTITION_KEY_PREFIX: &str = "__topic/";
+
+// every partition is a kafka log of its own, kept under this key
+pub fn partition_key(topic: &str, partition: usize) -> String {
+    format!("{PARTITION_KEY_PREFIX}{topic}/{partition}")
+}
+
+pub fn is_partition_key(key: &str) -> bool {
+    key.starts_with(PARTITION_KEY_PREFIX)
+}
+
+// partition key -> topic & partition it stands for
+pub type PartitionKeys = HashMap<String, (String, usize)>;
+
+fn hash_of(value: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+// topics not configured have a single partition
+pub(crate) struct Topics {
+    configs: HashMap<String, TopicConfig>,
+    // topic -> partition the next round-robin send goes to
+    next_partition: HashMap<String, usize>
+}
+
+impl Topics {
+
+    pub fn new() -> Self {
+        Topics {
+            configs: HashMap::new(),
+            next_partition: HashMap::new()
+        }
+    }
+
+    // every node has to be given the same topics, they decide where partitions live
+    pub fn configure(&mut self, topic: String, config: TopicConfig) {
+        self.configs.insert(topic, config);
+    }
+
+    pub fn config(&self, topic: &str) -> TopicConfig {
+        self.configs.get(topic).copied().unwrap_or_default()
+    }
+
+    pub fn check_partition(&self, topic: &str, partition: usize) -> Result<(), String> {
+        let partitions = self.config(topic).partitions;
+        match partition < partitions {
+            true => Ok(()),
+            false => Err(format!("topic {topic} has {partitions} partitions, there is no partition {partition}"))
+        }
+    }
+
+    // an explicit partition always wins over the topic's partitioner
+    pub fn pick_partition(&mut self, topic: &str, record_key: Option<&str>, explicit: Option<usize>) -> Result<usize, String> {
+        if let Some(partition) = explicit {
+            self.check_partition(topic, partition)?;
+            return Ok(partition)
+        }
+
+        let config = self.config(topic);
+        match (config.partitioner, record_key) {
+            (Partitioner::Hash, Some(record_key)) => Ok((hash_of(record_key) % config.partitions as u64) as usize),
+            _ => {
+                let next = self.next_partition.entry(String::from(topic)).or_insert(0);
+                let partition = *next % config.partitions;
+                *next = partition + 1;
+                Ok(partition)
+            }
+        }
+    }
+
+    // partitions of a configured topic go to consecutive nodes, starting at one picked by the topic's hash
+    // so a topic with as many partitions as there are nodes keeps every node busy
+    pub fn owner<'a>(&self, nodes: &'a [String], log_key: &str) -> Option<&'a String> {
+        let (topic, partition) = log_key.strip_prefix(PARTITION_KEY_PREFIX)?.rsplit_once('/')?;
+        let partition = partition.parse::<usize>().ok()?;
+        let config = self.configs.get(topic)?;
+        if partition >= config.partitions {
+            return None
+        }
+
+        let first = (hash_of(topic) % nodes.len() as u64) as usize;
+        Some(&nodes[(first + partition) % nodes.len()])
+    }
+}
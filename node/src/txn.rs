@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpKind {
+    #[serde(rename = "r")]
+    Read,
+    #[serde(rename = "w")]
+    Write,
+    #[serde(rename = "append")]
+    Append
+}
+
+// which maelstrom workload `txn` requests belong to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum TxnWorkload {
+    // registers kept on every node, see `TxnIsolation`
+    #[default]
+    RwRegister,
+    // serializable lists kept in lin-kv and lww-kv
+    ListAppend
+}
+
+// `[op, key, value]` as maelstrom sends it, reads carry null until the node fills them in
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MicroOp(pub OpKind, pub u64, pub serde_json::Value);
+
+// lamport clock of the transaction that wrote a value, the node id breaks ties
+// every node keeps the highest version per key, so they all agree on the order of conflicting writes
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Version {
+    pub counter: u64,
+    pub node: String
+}
+
+// writes of one transaction as its peers apply them
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ReplicatedTxn {
+    pub writes: Vec<(u64, serde_json::Value)>,
+    pub version: Version
+}
+
+// what a transaction exposes of its writes before it ends
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum TxnIsolation {
+    // writes reach the registers one by one and peers get every one of them in order
+    #[default]
+    ReadUncommitted,
+    // writes are buffered until the transaction ends and installed together
+    // peers only get the final value of every key, as one unit
+    ReadCommitted
+}
+
+// registers of the txn-rw-register workload, applied locally and replicated to peers afterwards
+pub(crate) struct TxnStore {
+    node_id: String,
+    isolation: TxnIsolation,
+    clock: u64,
+    // key -> value & version of the transaction that wrote it
+    values: HashMap<u64, (serde_json::Value, Version)>
+}
+
+impl TxnStore {
+
+    pub fn new(node_id: String, isolation: TxnIsolation) -> Self {
+        TxnStore {
+            node_id,
+            isolation,
+            clock: 0,
+            values: HashMap::new()
+        }
+    }
+
+    fn next_version(&mut self) -> Version {
+        self.clock += 1;
+        Version {
+            counter: self.clock,
+            node: self.node_id.clone()
+        }
+    }
+
+    // fills in the reads and returns the writes the peers need, see `TxnIsolation`
+    pub fn apply(&mut self, txn: Vec<MicroOp>) -> (Vec<MicroOp>, ReplicatedTxn) {
+        let version = self.next_version();
+        let mut writes: Vec<(u64, serde_json::Value)> = Vec::new();
+
+        let txn = txn.into_iter().map(|MicroOp(kind, key, value)| match kind {
+            OpKind::Read => {
+                //a transaction always sees its own writes
+                let own = writes.iter().rev().find(|(written, _)| *written == key).map(|(_, value)| value);
+                let read = own.or(self.values.get(&key).map(|(value, _)| value)).cloned().unwrap_or(serde_json::Value::Null);
+                MicroOp(kind, key, read)
+            },
+            OpKind::Write => {
+                if self.isolation == TxnIsolation::ReadUncommitted {
+                    self.values.insert(key, (value.clone(), version.clone()));
+                }
+                writes.push((key, value.clone()));
+                MicroOp(kind, key, value)
+            },
+            OpKind::Append => unreachable!("appends are refused before reaching the registers")
+        }).collect();
+
+        if self.isolation == TxnIsolation::ReadCommitted {
+            writes = final_writes(writes);
+            for (key, value) in &writes {
+                self.values.insert(*key, (value.clone(), version.clone()));
+            }
+        }
+
+        (txn, ReplicatedTxn {writes, version})
+    }
+
+    // writes of a peer's transaction, applied together, a key already holding a newer version keeps it
+    pub fn merge(&mut self, txn: ReplicatedTxn) {
+        let ReplicatedTxn {writes, version} = txn;
+        self.clock = self.clock.max(version.counter);
+        for (key, value) in writes {
+            let newer = self.values.get(&key).is_none_or(|(_, current)| *current <= version);
+            if newer {
+                self.values.insert(key, (value, version.clone()));
+            }
+        }
+    }
+}
+
+// last value written to every key, keys in the order they were first written
+fn final_writes(writes: Vec<(u64, serde_json::Value)>) -> Vec<(u64, serde_json::Value)> {
+    let mut positions: HashMap<u64, usize> = HashMap::new();
+    let mut finals: Vec<(u64, serde_json::Value)> = Vec::new();
+    for (key, value) in writes {
+        match positions.get(&key) {
+            Some(&position) => finals[position].1 = value,
+            None => {
+                positions.insert(key, finals.len());
+                finals.push((key, value));
+            }
+        }
+    }
+    finals
+}